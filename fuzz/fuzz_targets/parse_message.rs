@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/dns.rs"]
+mod dns;
+#[allow(dead_code)]
+#[path = "../../src/rr.rs"]
+mod rr;
+
+// Used by dns.rs, defined in main.rs
+const CLASS_IN: u16 = 1;
+const TYPE_PTR: u16 = 12;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = dns::parse_message(data);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/rr.rs"]
+mod rr;
+
+fuzz_target!(|data: &str| {
+    let _ = rr::unescape_name(data);
+});
@@ -0,0 +1,208 @@
+use std::{
+    ffi::{CStr, CString},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+};
+
+use clap::ValueEnum;
+
+use crate::{ratelimit::warn_limited, AF_INET4, AF_INET6};
+
+/// Which address families are used for tunnels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum FamilyPreference {
+    /// Only IPv4, devices without IPv4 addresses are skipped
+    Ipv4,
+    /// Only IPv6, devices without IPv6 addresses are skipped
+    Ipv6,
+    /// IPv4 if device advertises it, IPv6 otherwise
+    Ipv4ThenIpv6,
+    /// IPv6 if device advertises it, IPv4 otherwise
+    Ipv6ThenIpv4,
+}
+
+impl FamilyPreference {
+    /// Filter (ifindex, socket) pairs by preferred family
+    pub(crate) fn select(self, sockets: &[(i32, SocketAddr)]) -> Vec<(i32, SocketAddr)> {
+        let (v4, v6): (Vec<_>, Vec<_>) = sockets.iter().partition(|(_, s)| s.is_ipv4());
+        match self {
+            Self::Ipv4 => v4,
+            Self::Ipv6 => v6,
+            Self::Ipv4ThenIpv6 if v4.is_empty() => v6,
+            Self::Ipv4ThenIpv6 => v4,
+            Self::Ipv6ThenIpv4 if v6.is_empty() => v4,
+            Self::Ipv6ThenIpv4 => v6,
+        }
+    }
+}
+
+/// Network interface, resolved once on startup
+#[derive(Clone, Debug)]
+pub(crate) struct Interface {
+    pub name: String,
+    pub index: i32,
+}
+
+impl Interface {
+    pub(crate) fn parse(name: &str) -> io::Result<Self> {
+        let c_name = CString::new(name)?;
+        let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            index: index as i32,
+        })
+    }
+}
+
+/// Interfaces known to the kernel
+pub(crate) fn interfaces() -> io::Result<Vec<Interface>> {
+    let list = unsafe { libc::if_nameindex() };
+    if list.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let mut interfaces = Vec::new();
+    let mut entry = list;
+    unsafe {
+        // List is terminated by entry with zero index
+        while (*entry).if_index != 0 {
+            interfaces.push(Interface {
+                name: CStr::from_ptr((*entry).if_name)
+                    .to_string_lossy()
+                    .into_owned(),
+                index: (*entry).if_index as i32,
+            });
+            entry = entry.add(1);
+        }
+        libc::if_freenameindex(list);
+    }
+    Ok(interfaces)
+}
+
+pub(crate) fn family_name(socket: &SocketAddr) -> &'static str {
+    match socket {
+        SocketAddr::V4(_) => "IPv4",
+        SocketAddr::V6(_) => "IPv6",
+    }
+}
+
+/// Interface index with its name, if known, i.e `3 (eth0)`
+pub(crate) fn interface_display(ifindex: i32) -> String {
+    let mut buf = [0; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(ifindex as u32, buf.as_mut_ptr()) };
+    if name.is_null() {
+        return ifindex.to_string();
+    }
+    let name = unsafe { CStr::from_ptr(name) };
+    format!("{ifindex} ({})", name.to_string_lossy())
+}
+
+/// Unicast link-local address, `fe80::/10`
+///
+/// Same as unstable `Ipv6Addr::is_unicast_link_local`, to build on stable Rust
+fn is_unicast_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Build socket from resolve1 (ifindex, family, address) tuple
+///
+/// IPv4-mapped IPv6 addresses are converted to IPv4. Only link-local IPv6 addresses
+/// get `ifindex` as scope id, others get 0, as the scope would be ignored by the kernel,
+/// but would make the same device on different interfaces map to different tunnels.
+/// Link-local addresses without interface are rejected, as they can't be connected to
+pub(crate) fn socket_addr(ifindex: i32, af: i32, address: &[u8], port: u16) -> Option<SocketAddr> {
+    if af == AF_INET6 && address.len() == 16 {
+        let mut addr = [0; 16];
+        addr.copy_from_slice(address);
+        let addr = Ipv6Addr::from(addr);
+        // Dual-stack devices may be reported as both `a.b.c.d` and `::ffff:a.b.c.d`,
+        // use the same canonical form, so they map to the same tunnel
+        if let Some(addr) = addr.to_ipv4_mapped() {
+            return Some(SocketAddrV4::new(addr, port).into());
+        }
+        let scope_id = if is_unicast_link_local(&addr) {
+            if ifindex <= 0 {
+                warn_limited(format!(
+                    "link-local address {addr} without interface, ignoring"
+                ));
+                return None;
+            }
+            ifindex as u32
+        } else {
+            0
+        };
+        Some(SocketAddrV6::new(addr, port, 0, scope_id).into())
+    } else if af == AF_INET4 && address.len() == 4 {
+        let mut addr = [0; 4];
+        addr.copy_from_slice(address);
+        Some(SocketAddrV4::new(Ipv4Addr::from(addr), port).into())
+    } else {
+        warn_limited(format!("unknown address family: {af} {address:?}"));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_local_scope() {
+        let addr: Ipv6Addr = "fe80::1".parse().unwrap();
+        assert_eq!(
+            socket_addr(3, AF_INET6, &addr.octets(), 7000),
+            Some(SocketAddrV6::new(addr, 7000, 0, 3).into())
+        );
+    }
+
+    #[test]
+    fn global_scope() {
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            socket_addr(3, AF_INET6, &addr.octets(), 7000),
+            Some(SocketAddrV6::new(addr, 7000, 0, 0).into())
+        );
+        // Same device on other interface maps to the same socket
+        assert_eq!(
+            socket_addr(3, AF_INET6, &addr.octets(), 7000),
+            socket_addr(4, AF_INET6, &addr.octets(), 7000)
+        );
+    }
+
+    #[test]
+    fn ipv4() {
+        assert_eq!(
+            socket_addr(3, AF_INET4, &[192, 168, 1, 2], 7000),
+            Some(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 7000).into())
+        );
+    }
+
+    #[test]
+    fn link_local_without_interface() {
+        let addr: Ipv6Addr = "fe80::1".parse().unwrap();
+        assert_eq!(socket_addr(0, AF_INET6, &addr.octets(), 7000), None);
+        assert_eq!(socket_addr(-1, AF_INET6, &addr.octets(), 7000), None);
+    }
+
+    #[test]
+    fn unknown_family() {
+        assert_eq!(socket_addr(2, 7, &[1, 2, 3, 4], 7000), None);
+        // Length doesn't match the family
+        assert_eq!(socket_addr(2, AF_INET4, &[1, 2, 3], 7000), None);
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let mapped: Ipv6Addr = "::ffff:192.168.1.2".parse().unwrap();
+        assert_eq!(
+            socket_addr(2, AF_INET6, &mapped.octets(), 7000),
+            Some("192.168.1.2:7000".parse().unwrap())
+        );
+        assert_eq!(
+            socket_addr(2, AF_INET6, &mapped.octets(), 7000),
+            socket_addr(2, AF_INET4, &[192, 168, 1, 2], 7000)
+        );
+    }
+}
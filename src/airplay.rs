@@ -0,0 +1,578 @@
+use std::{fmt, num::ParseIntError};
+
+use serde::Deserialize;
+
+use crate::{
+    rr::strip_name_suffix,
+    txt::{list_contains, parse_bool_txt, Txt},
+};
+
+/// Decoded `sf=` TXT field
+///
+/// See https://openairplay.github.io/airplay-spec/status_flags.html
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct StatusFlags(u32);
+
+impl StatusFlags {
+    const NAMES: &'static [(u32, &'static str)] = &[
+        (1 << 0, "problem-detected"),
+        (1 << 1, "not-configured"),
+        (1 << 2, "audio-cable-attached"),
+        (1 << 3, "pin-required"),
+        (1 << 6, "supports-airplay-from-cloud"),
+        (1 << 7, "password-required"),
+        (1 << 9, "one-time-pairing-required"),
+        (1 << 10, "setup-for-hk-access-control"),
+        (1 << 11, "supports-relay"),
+        (1 << 12, "silent-primary"),
+        (1 << 13, "tight-sync-is-group-leader"),
+        (1 << 14, "tight-sync-buddy-not-reachable"),
+        (1 << 15, "is-apple-music-subscriber"),
+        (1 << 16, "icloud-library-is-on"),
+        (1 << 17, "receiver-session-is-active"),
+    ];
+    const PASSWORD_REQUIRED: u32 = 1 << 7;
+    const RECEIVER_SESSION_IS_ACTIVE: u32 = 1 << 17;
+
+    /// Value is a hex bitmask, with or without `0x` prefix
+    pub(crate) fn parse(value: &str) -> Result<Self, ParseIntError> {
+        let value = value.trim();
+        let hex = value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+            .unwrap_or(value);
+        u32::from_str_radix(hex, 16).map(Self)
+    }
+
+    pub(crate) fn password_required(self) -> bool {
+        self.0 & Self::PASSWORD_REQUIRED != 0
+    }
+
+    /// Device is currently streaming from another sender
+    pub(crate) fn is_busy(self) -> bool {
+        self.0 & Self::RECEIVER_SESSION_IS_ACTIVE != 0
+    }
+}
+
+impl fmt::Debug for StatusFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_set();
+        let mut unknown = self.0;
+        for (bit, name) in Self::NAMES {
+            if self.0 & bit != 0 {
+                list.entry(&format_args!("{name}"));
+                unknown &= !bit;
+            }
+        }
+        if unknown != 0 {
+            list.entry(&format_args!("{unknown:#x}"));
+        }
+        list.finish()
+    }
+}
+
+/// AirPlay server version from `vs=`, i.e `366.0`
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ServerVersion(Vec<u32>);
+
+impl ServerVersion {
+    pub(crate) fn parse(value: &str) -> Result<Self, ParseIntError> {
+        value
+            .trim()
+            .split('.')
+            .map(|v| v.parse())
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    fn major(&self) -> u32 {
+        self.0.first().copied().unwrap_or(0)
+    }
+
+    /// AirPlay 2 firmwares, which handle auth_setup better than RSA
+    pub(crate) fn prefers_auth_setup(&self) -> bool {
+        self.major() >= 300
+    }
+
+    /// Versions which are known to have problems with raop-sink
+    pub(crate) fn known_issue(&self) -> Option<&'static str> {
+        if self.major() < 200 {
+            return Some("legacy AirTunes firmware (original AirPort Express), only RSA encryption and ALAC are supported");
+        }
+        None
+    }
+}
+
+impl fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, part) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{part}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Device specific property overrides, required for device to actually play
+pub(crate) struct Quirk {
+    /// `am=` value
+    model: &'static str,
+    /// Quirk only applies to versions below this
+    below_version: Option<u32>,
+    pub description: &'static str,
+    pub encryption: Option<&'static str>,
+    pub latency_ms: Option<u32>,
+}
+
+impl Quirk {
+    /// Only applies when ALAC codec is selected
+    pub(crate) fn find(model: &str, version: Option<&ServerVersion>) -> Option<&'static Self> {
+        QUIRKS.iter().find(|q| {
+            q.model == model
+                && match (q.below_version, version) {
+                    (Some(below), Some(version)) => version.major() < below,
+                    (Some(_), None) | (None, _) => true,
+                }
+        })
+    }
+}
+
+/// Legacy AirPort Express units only accept RSA encrypted ALAC stream,
+/// and expect classic AirTunes 2s buffer
+const QUIRKS: &[Quirk] = &[
+    Quirk {
+        model: "AirPort4,107",
+        below_version: None,
+        description: "AirPort Express (1st generation)",
+        encryption: Some("RSA"),
+        latency_ms: Some(2000),
+    },
+    Quirk {
+        model: "AirPort10,115",
+        below_version: Some(200),
+        description: "AirPort Express (2nd generation, legacy firmware)",
+        encryption: Some("RSA"),
+        latency_ms: Some(2000),
+    },
+];
+
+/// Supported features bitmask from `ft=`/`features=`
+///
+/// Value is either a single hex number, or two comma-separated 32 bit halves (low, high)
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Features(u64);
+
+impl Features {
+    pub(crate) fn parse(value: &str) -> Result<Self, ParseIntError> {
+        fn hex(v: &str) -> Result<u64, ParseIntError> {
+            let v = v.trim();
+            let v = v
+                .strip_prefix("0x")
+                .or_else(|| v.strip_prefix("0X"))
+                .unwrap_or(v);
+            u64::from_str_radix(v, 16)
+        }
+        Ok(Self(match value.split_once(',') {
+            Some((low, high)) => hex(low)? | hex(high)? << 32,
+            None => hex(value)?,
+        }))
+    }
+}
+
+impl fmt::Debug for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// User-assigned name from the service instance label of the raw (not escaped) name,
+/// i.e `Living Room` for `001122334455@Living Room._raop._tcp.local`
+pub(crate) fn instance_name(domain: &str, service: &str) -> Option<String> {
+    device_name(strip_name_suffix(domain, service)?)
+}
+
+/// Name of the device from unescaped instance label
+pub(crate) fn device_name(instance: &str) -> Option<String> {
+    // RAOP instances are prefixed with device MAC address
+    let name = match instance.split_once('@') {
+        Some((mac, name)) if !mac.is_empty() && mac.chars().all(|c| c.is_ascii_hexdigit()) => name,
+        _ => instance,
+    };
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Device identity from the RAOP instance label MAC prefix, i.e `001122334455` for
+/// `001122334455@Living Room`, or `deviceid=` of the AirPlay service, i.e `00:11:22:33:44:55`
+pub(crate) fn device_id(value: &str) -> Option<String> {
+    let mac = value.split_once('@').map_or(value, |(mac, _)| mac);
+    let id: String = mac
+        .chars()
+        .filter(|c| *c != ':' && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    (id.len() == 12 && id.chars().all(|c| c.is_ascii_hexdigit())).then_some(id)
+}
+
+/// `_airplay._tcp` service of the device, which advertises the same capabilities
+/// under different TXT keys, and usually has a better name
+#[derive(Clone, Debug)]
+pub(crate) struct AirplayService {
+    pub name: Option<String>,
+    pub txt: Txt,
+}
+
+impl AirplayService {
+    /// `_airplay._tcp` keys and their RAOP counterparts
+    const KEYS: &'static [(&'static str, &'static str)] = &[
+        ("features", "ft"),
+        ("flags", "sf"),
+        ("model", "am"),
+        ("srcvers", "vs"),
+        ("pk", "pk"),
+        ("gid", "gid"),
+        ("gpn", "gpn"),
+    ];
+
+    /// RAOP TXT records, with fields missing there taken from the AirPlay service
+    pub(crate) fn merge(&self, raop: &Txt) -> Txt {
+        let mut records = raop.records().to_vec();
+        for (airplay, key) in Self::KEYS {
+            if raop.get(key).is_some() {
+                continue;
+            }
+            if let Some(value) = self.txt.get(airplay) {
+                records.push(format!("{key}={value}"));
+            }
+        }
+        Txt::new(records)
+    }
+}
+
+/// AirPlay 2 group membership (stereo pairs, multi-room groups), from `gid=`/`gpn=`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Group {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+impl Group {
+    pub(crate) fn from_txt(txt: &Txt) -> Option<Self> {
+        let id = txt.get("gid")?;
+        let name = txt.get("gpn");
+        Some(Self {
+            id: id.to_owned(),
+            name: name.map(|v| v.to_owned()),
+        })
+    }
+}
+
+/// Audio codec, as listed in `cn=`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING-KEBAB-CASE")]
+pub(crate) enum Codec {
+    Pcm,
+    Alac,
+    Aac,
+    AacEld,
+}
+
+impl Codec {
+    pub(crate) fn cn(self) -> u8 {
+        match self {
+            Self::Pcm => 0,
+            Self::Alac => 1,
+            Self::Aac => 2,
+            Self::AacEld => 3,
+        }
+    }
+
+    /// Name accepted by raop-sink
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Pcm => "PCM",
+            Self::Alac => "ALAC",
+            Self::Aac => "AAC",
+            Self::AacEld => "AAC-ELD",
+        }
+    }
+
+    /// Parse name accepted by raop-sink, case-insensitive
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        [Self::Pcm, Self::Alac, Self::Aac, Self::AacEld]
+            .into_iter()
+            .find(|codec| codec.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Most preferred codec from the comma-separated `cn=` list
+    pub(crate) fn from_cn(list: &str) -> Option<Self> {
+        [Self::AacEld, Self::Aac, Self::Alac, Self::Pcm]
+            .into_iter()
+            .find(|codec| list_contains(list, &codec.cn().to_string()))
+    }
+}
+
+/// Now playing metadata types supported by the device, from `md=`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) struct Metadata {
+    pub text: bool,
+    pub artwork: bool,
+    pub progress: bool,
+}
+
+impl Metadata {
+    pub(crate) fn from_md(list: &str) -> Self {
+        Self {
+            text: list_contains(list, "0"),
+            artwork: list_contains(list, "1"),
+            progress: list_contains(list, "2"),
+        }
+    }
+}
+
+/// Supported types separated by comma, i.e `text,artwork`, or `none`
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let types: Vec<_> = [
+            ("text", self.text),
+            ("artwork", self.artwork),
+            ("progress", self.progress),
+        ]
+        .into_iter()
+        .filter_map(|(name, supported)| supported.then_some(name))
+        .collect();
+        if types.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&types.join(","))
+        }
+    }
+}
+
+/// Audio capabilities from `da=` (digital audio) and `sv=` (software volume),
+/// None if not advertised
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct AudioFlags {
+    pub digital_audio: Option<bool>,
+    pub software_volume: Option<bool>,
+}
+
+impl AudioFlags {
+    pub(crate) fn from_txt(txt: &Txt) -> Self {
+        Self {
+            digital_audio: txt.get("da").map(parse_bool_txt),
+            software_volume: txt.get("sv").map(parse_bool_txt),
+        }
+    }
+}
+
+impl fmt::Display for AudioFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = |v: Option<bool>| v.map_or("-", |v| if v { "true" } else { "false" });
+        write!(
+            f,
+            "da={} sv={}",
+            value(self.digital_audio),
+            value(self.software_volume)
+        )
+    }
+}
+
+/// Encryption type, as listed in `et=`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Encryption {
+    None,
+    Rsa,
+    AuthSetup,
+    /// FairPlay (3) or FairPlay SAP v2.5 (5)
+    FairPlay,
+}
+
+impl Encryption {
+    /// Most preferred encryption from the comma-separated `et=` list,
+    /// FairPlay is only returned when nothing else is advertised
+    pub(crate) fn from_et(list: &str, prefer_auth_setup: bool) -> Option<Self> {
+        let (first, second) = if prefer_auth_setup {
+            ((Self::AuthSetup, "4"), (Self::Rsa, "1"))
+        } else {
+            ((Self::Rsa, "1"), (Self::AuthSetup, "4"))
+        };
+        [
+            first,
+            second,
+            (Self::None, "0"),
+            (Self::FairPlay, "3"),
+            (Self::FairPlay, "5"),
+        ]
+        .into_iter()
+        .find(|(_, et)| list_contains(list, et))
+        .map(|(encryption, _)| encryption)
+    }
+
+    /// Both RSA and auth_setup are advertised, so the choice depends on preference
+    pub(crate) fn is_ambiguous(list: &str) -> bool {
+        list_contains(list, "1") && list_contains(list, "4")
+    }
+
+    /// Name accepted by raop-sink, None if it isn't supported by the module
+    pub(crate) fn name(self) -> Option<&'static str> {
+        match self {
+            Self::None => Some("none"),
+            Self::Rsa => Some("RSA"),
+            Self::AuthSetup => Some("auth_setup"),
+            Self::FairPlay => None,
+        }
+    }
+}
+
+/// Transport, as listed in `tp=`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    pub(crate) fn tp(self) -> &'static str {
+        match self {
+            Self::Udp => "UDP",
+            Self::Tcp => "TCP",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_flags() {
+        let busy = StatusFlags::parse("0x20000").unwrap();
+        assert!(busy.is_busy() && !busy.password_required());
+        // iCloud library bit is next to the session one
+        assert!(!StatusFlags::parse("0x10000").unwrap().is_busy());
+        let password = StatusFlags::parse("80").unwrap();
+        assert!(password.password_required() && !password.is_busy());
+        assert!(!StatusFlags::parse("0x40").unwrap().password_required());
+        assert_eq!(
+            format!("{:?}", StatusFlags::parse("0x30084").unwrap()),
+            "{audio-cable-attached, password-required, icloud-library-is-on, receiver-session-is-active}"
+        );
+        assert_eq!(
+            format!("{:?}", StatusFlags::parse("0x110").unwrap()),
+            "{0x110}"
+        );
+    }
+
+    #[test]
+    fn server_version() {
+        let version = ServerVersion::parse(" 366.0").unwrap();
+        assert_eq!(version.to_string(), "366.0");
+        assert!(ServerVersion::parse("105.1").unwrap() < version);
+        assert!(ServerVersion::parse("366.0.1").unwrap() > version);
+        assert!(ServerVersion::parse("366.a").is_err());
+        assert!(ServerVersion::parse("").is_err());
+        assert!(ServerVersion::parse("105.1")
+            .unwrap()
+            .known_issue()
+            .is_some());
+        assert!(version.known_issue().is_none());
+    }
+
+    #[test]
+    fn features() {
+        assert_eq!(
+            Features::parse("0x5A7FFFF7,0x1E").unwrap(),
+            Features(0x1e_5a7f_fff7)
+        );
+        assert_eq!(
+            Features::parse("0x445F8A00").unwrap(),
+            Features(0x445f_8a00)
+        );
+        assert_eq!(
+            Features::parse("5a7ffff7, 1e").unwrap(),
+            Features(0x1e_5a7f_fff7)
+        );
+        assert!(Features::parse("0x5A7FFFF7,").is_err());
+        assert!(Features::parse("none").is_err());
+        assert_eq!(format!("{:?}", Features(0x1e)), "0x1e");
+    }
+
+    #[test]
+    fn instance_name_raw() {
+        assert_eq!(
+            instance_name(
+                "001122334455@Living Room._raop._tcp.local",
+                "_raop._tcp.local"
+            )
+            .as_deref(),
+            Some("Living Room")
+        );
+        // Label with a dot
+        assert_eq!(
+            instance_name("Kitchen.Left._raop._tcp.local.", "_raop._tcp.local").as_deref(),
+            Some("Kitchen.Left")
+        );
+        // Raw labels aren't unescaped
+        assert_eq!(
+            instance_name(r"Living\032Room._raop._tcp.local", "_raop._tcp.local").as_deref(),
+            Some(r"Living\032Room")
+        );
+        assert_eq!(
+            instance_name("Café._raop._tcp.local", "_raop._tcp.local").as_deref(),
+            Some("Café")
+        );
+    }
+
+    #[test]
+    fn instance_name_other_service() {
+        assert_eq!(
+            instance_name("Living._airplay._tcp.local", "_raop._tcp.local"),
+            None
+        );
+        // Only MAC prefix, no name
+        assert_eq!(
+            instance_name("001122334455@._raop._tcp.local", "_raop._tcp.local"),
+            None
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = Metadata::from_md("0, 2");
+        assert!(metadata.text && !metadata.artwork && metadata.progress);
+        assert_eq!(metadata.to_string(), "text,progress");
+        assert_eq!(Metadata::from_md("").to_string(), "none");
+    }
+
+    #[test]
+    fn encryption_from_et() {
+        assert_eq!(Encryption::from_et("0", false), Some(Encryption::None));
+        assert_eq!(Encryption::from_et("0,1", false), Some(Encryption::Rsa));
+        assert_eq!(Encryption::from_et("0,3,5", false), Some(Encryption::None));
+        assert_eq!(Encryption::from_et("3,5", true), Some(Encryption::FairPlay));
+        assert_eq!(Encryption::from_et("7", false), None);
+        assert!(!Encryption::is_ambiguous("0,1"));
+    }
+
+    #[test]
+    fn encryption_from_et_by_version() {
+        let list = "1,4";
+        assert!(Encryption::is_ambiguous(list));
+        for (vs, expected) in [
+            ("105.1", Encryption::Rsa),
+            ("220.68", Encryption::Rsa),
+            ("300.0", Encryption::AuthSetup),
+            ("366.0", Encryption::AuthSetup),
+        ] {
+            let version = ServerVersion::parse(vs).unwrap();
+            assert_eq!(
+                Encryption::from_et(list, version.prefers_auth_setup()),
+                Some(expected),
+                "vs={vs}"
+            );
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{cell::RefCell, rc::Rc};
+
+use pipewire::Properties;
+
+use crate::{
+    config::Config,
+    loader::{ModuleHandle, ModuleLoader},
+    opts::Opts,
+    properties::raop_properties,
+    Discovered, Result,
+};
+
+/// Creates sinks for discovered devices, sink is removed when the returned handle is dropped
+pub(crate) trait SinkBackend {
+    /// `tags` should be set on the created sink node
+    fn load(
+        &self,
+        opts: &Opts,
+        msg: &Discovered,
+        name: &str,
+        tags: Properties,
+    ) -> Result<ModuleHandle>;
+}
+
+/// RAOP sink, created by `--module`
+pub(crate) struct RaopBackend {
+    pub loader: Rc<dyn ModuleLoader>,
+    /// Codec latency is read from the current config, which is replaced on reload
+    pub config: Rc<RefCell<Config>>,
+}
+
+impl SinkBackend for RaopBackend {
+    fn load(
+        &self,
+        opts: &Opts,
+        msg: &Discovered,
+        name: &str,
+        tags: Properties,
+    ) -> Result<ModuleHandle> {
+        let latency = self.config.borrow().latency;
+        ModuleHandle::load(
+            &self.loader,
+            raop_properties(msg, opts, &latency, name),
+            tags,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use pipewire::properties;
+
+    use super::*;
+    use crate::{
+        testing::{discovered, LoaderCall, NoopBackend, RecordingLoader},
+        KEY_PROP,
+    };
+
+    fn opts() -> Opts {
+        Opts::parse_from(["pw-resolved-discover"])
+    }
+
+    #[test]
+    fn noop_backend_remembers_names() {
+        let backend = NoopBackend::default();
+        let msg = discovered("a.local", "192.168.1.2:7000", "A._raop._tcp.local");
+        let handle = backend.load(&opts(), &msg, "A", Properties::new());
+        assert!(handle.is_ok());
+        drop(handle);
+        assert_eq!(*backend.names.borrow(), ["A"]);
+    }
+
+    #[test]
+    fn raop_backend_unloads_on_drop() {
+        let loader = Rc::new(RecordingLoader::default());
+        let backend = RaopBackend {
+            loader: loader.clone(),
+            config: Rc::default(),
+        };
+        let msg = discovered("a.local", "192.168.1.2:7000", "A._raop._tcp.local");
+        let tags = properties! { KEY_PROP => "a" };
+        let handle = backend.load(&opts(), &msg, "A", tags).unwrap();
+        assert_eq!(*loader.calls.borrow(), [LoaderCall::Load("a".to_owned())]);
+        drop(handle);
+        assert_eq!(
+            *loader.calls.borrow(),
+            [
+                LoaderCall::Load("a".to_owned()),
+                LoaderCall::Unload("a".to_owned())
+            ]
+        );
+    }
+}
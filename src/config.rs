@@ -0,0 +1,140 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    airplay::{Codec, Transport},
+    resolver::{ResolveFlag, ResolveFlags},
+    txt::Txt,
+    Discovered, Result,
+};
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Devices unreachable by mDNS, i.e placed in other VLAN
+    #[serde(default, rename = "static")]
+    pub static_devices: Vec<StaticDevice>,
+    /// Only applied on startup
+    #[serde(default)]
+    pub resolve: ResolveConfig,
+    #[serde(default)]
+    pub latency: LatencyConfig,
+}
+
+/// Latency of sinks per codec, in milliseconds, `--codec-latency` takes precedence
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub(crate) struct LatencyConfig {
+    #[serde(rename = "PCM")]
+    pub pcm: u32,
+    #[serde(rename = "ALAC")]
+    pub alac: u32,
+    #[serde(rename = "AAC")]
+    pub aac: u32,
+    #[serde(rename = "AAC-ELD")]
+    pub aac_eld: u32,
+}
+
+impl Default for LatencyConfig {
+    /// Lossless streams need larger buffer to survive retransmissions over wifi,
+    /// AAC-ELD is meant for low latency
+    fn default() -> Self {
+        Self {
+            pcm: 2000,
+            alac: 2000,
+            aac: 1500,
+            aac_eld: 500,
+        }
+    }
+}
+
+impl LatencyConfig {
+    pub(crate) fn latency_ms(&self, codec: Codec) -> u32 {
+        match codec {
+            Codec::Pcm => self.pcm,
+            Codec::Alac => self.alac,
+            Codec::Aac => self.aac,
+            Codec::AacEld => self.aac_eld,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ResolveConfig {
+    /// Additional flags of resolve1 calls
+    #[serde(default)]
+    pub flags: Vec<ResolveFlag>,
+}
+
+impl ResolveConfig {
+    pub(crate) fn flags(&self) -> ResolveFlags {
+        self.flags
+            .iter()
+            .fold(ResolveFlags::default(), |acc, f| acc | f.flags())
+    }
+}
+
+impl Config {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)?;
+        Ok(toml::from_str(&data)?)
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StaticDevice {
+    pub address: IpAddr,
+    pub port: u16,
+    pub name: String,
+    /// Defaults to address
+    pub hostname: Option<String>,
+    pub codec: Option<Codec>,
+    pub transport: Option<Transport>,
+}
+
+impl StaticDevice {
+    /// Device as if it was discovered, TXT records are synthesized from the config
+    pub(crate) fn discovered(&self) -> Discovered {
+        let mut records = vec![format!("am={}", self.name)];
+        if let Some(codec) = self.codec {
+            records.push(format!("cn={}", codec.cn()));
+        }
+        if let Some(transport) = self.transport {
+            records.push(format!("tp={}", transport.tp()));
+        }
+        Discovered {
+            hostname: self
+                .hostname
+                .clone()
+                .unwrap_or_else(|| self.address.to_string()),
+            socket: SocketAddr::new(self.address, self.port),
+            ifindex: 0,
+            domain: None,
+            instance: None,
+            canonical: None,
+            txt: Txt::new(records),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency() {
+        let config: Config = toml::from_str("[latency]\nALAC = 3000\n").unwrap();
+        assert_eq!(config.latency.latency_ms(Codec::Alac), 3000);
+        assert_eq!(config.latency.latency_ms(Codec::AacEld), 500);
+        let default = LatencyConfig::default();
+        assert!(default.latency_ms(Codec::Alac) > default.latency_ms(Codec::AacEld));
+        assert!(toml::from_str::<Config>("[latency]\nOPUS = 100\n").is_err());
+    }
+}
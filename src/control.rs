@@ -0,0 +1,110 @@
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::trigger::ScanTrigger;
+
+/// Clients which don't send the command in time are disconnected
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Request sent by the client, one line
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Command {
+    /// Tunnel table
+    Status,
+    /// Scan now, instead of waiting for the next interval
+    Scan,
+}
+
+impl Command {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::Scan => "scan",
+        }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        [Self::Status, Self::Scan]
+            .into_iter()
+            .find(|c| c.name() == line.trim())
+    }
+}
+
+/// Default control socket, in the runtime directory of the user
+pub(crate) fn default_socket() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map_or_else(|| PathBuf::from("/run"), PathBuf::from)
+        .join("pw-resolved-discover.sock")
+}
+
+/// Serve control commands
+///
+/// Tunnels are owned by the main loop, so it only updates the status snapshot, and
+/// clients are served from a separate thread
+pub(crate) fn serve(path: &Path, trigger: ScanTrigger) -> io::Result<Arc<Mutex<String>>> {
+    // Left by a previous instance which didn't exit cleanly
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another instance is running",
+            ))
+        }
+        Err(_) if path.exists() => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("control socket is listening on {}", path.display());
+    let status = Arc::new(Mutex::new(String::new()));
+    std::thread::spawn({
+        let status = status.clone();
+        move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| handle(stream, &status, &trigger));
+                if let Err(e) = result {
+                    debug!("failed to serve control client: {e}");
+                }
+            }
+            warn!("control socket is closed");
+        }
+    });
+    Ok(status)
+}
+
+fn handle(mut stream: UnixStream, status: &Mutex<String>, trigger: &ScanTrigger) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    match Command::parse(&line) {
+        Some(Command::Status) => {
+            let status = status.lock().expect("not poisoned").clone();
+            stream.write_all(status.as_bytes())
+        }
+        Some(Command::Scan) => {
+            info!("scan requested");
+            trigger.trigger();
+            Ok(())
+        }
+        None => {
+            debug!("unknown control command: {line:?}");
+            Ok(())
+        }
+    }
+}
+
+/// Send command to the running instance, returns its response
+pub(crate) fn send(path: &Path, command: Command) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command.name())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
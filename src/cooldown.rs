@@ -0,0 +1,176 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::TunnelKey;
+
+struct Entry {
+    removed: Instant,
+    /// When the device reappeared after removal
+    present_since: Option<Instant>,
+}
+
+/// Delays re-adding of recently removed devices, so a device flapping at the
+/// removal boundary doesn't cause sink churn
+///
+/// Device which reappears within `duration` after removal only gets its sink back
+/// once it stays present for `duration`
+pub(crate) struct Cooldown {
+    duration: Duration,
+    entries: RefCell<HashMap<TunnelKey, Entry>>,
+}
+
+impl Cooldown {
+    pub(crate) fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            entries: RefCell::default(),
+        }
+    }
+
+    pub(crate) fn removed(&self, key: TunnelKey) {
+        if self.duration.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.borrow_mut();
+        // Devices which didn't reappear in time aren't interesting anymore
+        entries.retain(|_, e| e.present_since.is_some() || now - e.removed < self.duration);
+        entries.insert(
+            key,
+            Entry {
+                removed: now,
+                present_since: None,
+            },
+        );
+    }
+
+    /// Whether sink of the discovered device may be created
+    pub(crate) fn allows(&self, key: &TunnelKey) -> bool {
+        let mut entries = self.entries.borrow_mut();
+        let Some(entry) = entries.get_mut(key) else {
+            return true;
+        };
+        let now = Instant::now();
+        match entry.present_since {
+            None if now - entry.removed >= self.duration => {}
+            None => {
+                info!(
+                    "{key:?} reappeared shortly after removal, waiting {:?} before re-adding",
+                    self.duration
+                );
+                entry.present_since = Some(now);
+                return false;
+            }
+            Some(since) if now - since < self.duration => return false,
+            Some(_) => info!("{key:?} is stable, leaving cooldown"),
+        }
+        entries.remove(key);
+        true
+    }
+}
+
+/// Keeps devices from being retried for `duration` after a failure
+///
+/// Unlike [`Cooldown`], device doesn't have to stay present, it is retried as soon
+/// as `duration` passes
+pub(crate) struct Backoff {
+    duration: Duration,
+    failed: RefCell<HashMap<TunnelKey, Instant>>,
+}
+
+impl Backoff {
+    pub(crate) fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            failed: RefCell::default(),
+        }
+    }
+
+    pub(crate) fn failed(&self, key: TunnelKey) {
+        if self.duration.is_zero() {
+            return;
+        }
+        self.failed.borrow_mut().insert(key, Instant::now());
+    }
+
+    /// Whether the device may be retried
+    pub(crate) fn allows(&self, key: &TunnelKey) -> bool {
+        let mut failed = self.failed.borrow_mut();
+        match failed.get(key) {
+            Some(at) if at.elapsed() < self.duration => false,
+            Some(_) => {
+                failed.remove(key);
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const DURATION: Duration = Duration::from_millis(50);
+
+    fn key() -> TunnelKey {
+        TunnelKey {
+            hostname: "a.local".to_owned(),
+            socket: "192.168.1.2:7000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn flapping_device_waits() {
+        let cooldown = Cooldown::new(DURATION);
+        assert!(cooldown.allows(&key()));
+        cooldown.removed(key());
+        assert!(!cooldown.allows(&key()));
+        assert!(!cooldown.allows(&key()));
+        sleep(DURATION);
+        assert!(cooldown.allows(&key()));
+        // Cooldown is left
+        assert!(cooldown.allows(&key()));
+    }
+
+    #[test]
+    fn late_reappearance_is_allowed() {
+        let cooldown = Cooldown::new(DURATION);
+        cooldown.removed(key());
+        sleep(DURATION);
+        assert!(cooldown.allows(&key()));
+    }
+
+    #[test]
+    fn disabled() {
+        let cooldown = Cooldown::new(Duration::ZERO);
+        cooldown.removed(key());
+        assert!(cooldown.allows(&key()));
+    }
+
+    #[test]
+    fn backoff_retries_after_duration() {
+        let backoff = Backoff::new(DURATION);
+        assert!(backoff.allows(&key()));
+        backoff.failed(key());
+        assert!(!backoff.allows(&key()));
+        sleep(DURATION);
+        // Device doesn't have to be seen again during the backoff
+        assert!(backoff.allows(&key()));
+        assert!(backoff.allows(&key()));
+    }
+
+    #[test]
+    fn backoff_disabled() {
+        let backoff = Backoff::new(Duration::ZERO);
+        backoff.failed(key());
+        assert!(backoff.allows(&key()));
+    }
+}
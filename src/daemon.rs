@@ -0,0 +1,33 @@
+use std::{fs::OpenOptions, io, os::fd::AsRawFd, process};
+
+/// Detach from the terminal using the double fork
+///
+/// Only the calling thread survives the fork, so this should be called before any
+/// threads are spawned, and before pipewire or D-Bus connections are created.
+/// Working directory is kept, so relative paths passed in options stay valid
+pub(crate) fn daemonize() -> io::Result<()> {
+    fork_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Session leader might acquire controlling terminal, second child never will
+    fork_exit_parent()?;
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn fork_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
@@ -0,0 +1,407 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use nom::{
+    bytes::complete::take,
+    error::{Error, ErrorKind},
+    number::complete::{be_u16, be_u32, be_u8},
+    IResult,
+};
+
+use crate::{
+    rr::{all_consumed, split_class},
+    CLASS_IN, TYPE_PTR,
+};
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_TXT: u16 = 16;
+pub(crate) const TYPE_AAAA: u16 = 28;
+pub(crate) const TYPE_SRV: u16 = 33;
+
+/// Recursion desired, for queries to unicast servers
+pub(crate) const FLAG_RD: u16 = 0x0100;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+
+/// Response code of nonexistent name
+pub(crate) const RCODE_NXDOMAIN: u16 = 3;
+
+/// Maximum length of name, RFC 1035 2.3.4
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_LABEL_LENGTH: usize = 63;
+/// Compression pointers followed in a single name, to reject pointer loops
+const MAX_POINTERS: usize = 16;
+
+/// DNS message, only the parts needed for service discovery
+#[derive(Debug)]
+pub(crate) struct Message {
+    pub id: u16,
+    pub flags: u16,
+    /// Records of answer, authority and additional sections
+    pub records: Vec<Record>,
+}
+
+impl Message {
+    pub(crate) fn is_response(&self) -> bool {
+        self.flags & FLAG_QR != 0
+    }
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.flags & FLAG_TC != 0
+    }
+    pub(crate) fn rcode(&self) -> u16 {
+        self.flags & 0xf
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Record {
+    pub name: String,
+    pub type_: u16,
+    /// Without mDNS cache-flush bit
+    pub class: u16,
+    #[allow(unused)]
+    pub ttl: u32,
+    pub data: RData,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum RData {
+    Ptr(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<Vec<u8>>),
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Other,
+}
+
+/// Encode query with a single IN question, returns None if name has labels too long to be encoded
+///
+/// Name is split on dots, labels containing dots themselves can't be queried
+pub(crate) fn encode_query(id: u16, flags: u16, name: &str, type_: u16) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(12 + name.len() + 6);
+    for v in [id, flags, 1, 0, 0, 0] {
+        out.extend_from_slice(&v.to_be_bytes());
+    }
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        if label.len() > MAX_LABEL_LENGTH {
+            return None;
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out.extend_from_slice(&type_.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(out)
+}
+
+/// Parse name, which might be compressed (RFC 1035 4.1.4), pointers are relative to `message`
+fn parse_name<'a>(message: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], String> {
+    let mut res = String::new();
+    let mut i = input;
+    // Name ends after the first pointer
+    let mut rest = None;
+    let mut pointers = 0;
+    loop {
+        match be_u8(i)? {
+            (remaining, 0) => return Ok((rest.unwrap_or(remaining), res)),
+            (remaining, length) if length & 0xc0 == 0xc0 => {
+                let (remaining, low) = be_u8(remaining)?;
+                let offset = ((length & 0x3f) as usize) << 8 | low as usize;
+                pointers += 1;
+                if pointers > MAX_POINTERS || offset >= message.len() {
+                    return Err(nom::Err::Error(Error::new(i, ErrorKind::Verify)));
+                }
+                rest.get_or_insert(remaining);
+                i = &message[offset..];
+            }
+            (_, length) if length & 0xc0 != 0 => {
+                // Reserved label types
+                return Err(nom::Err::Error(Error::new(i, ErrorKind::Verify)));
+            }
+            (remaining, length) => {
+                let (remaining, label) = take(length)(remaining)?;
+                if !res.is_empty() {
+                    res.push('.');
+                }
+                res.push_str(&String::from_utf8_lossy(label));
+                if res.len() > MAX_NAME_LENGTH {
+                    return Err(nom::Err::Error(Error::new(i, ErrorKind::TooLarge)));
+                }
+                i = remaining;
+            }
+        }
+    }
+}
+
+/// Character strings of TXT record, RFC 1035 3.3.14
+fn parse_txt(mut input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
+    let mut strings = Vec::new();
+    while !input.is_empty() {
+        let (remaining, length) = be_u8(input)?;
+        let (remaining, string) = take(length)(remaining)?;
+        strings.push(string.to_vec());
+        input = remaining;
+    }
+    Ok((input, strings))
+}
+
+fn parse_record<'a>(message: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], Record> {
+    let (input, name) = parse_name(message, input)?;
+    let (input, type_) = be_u16(input)?;
+    let (input, class) = be_u16(input)?;
+    let (class, _cache_flush) = split_class(class);
+    let (input, ttl) = be_u32(input)?;
+    let (input, rd_length) = be_u16(input)?;
+    let (input, rdata) = take(rd_length)(input)?;
+    // Typed rdata should take exactly `rd_length` bytes. Compressed names only have
+    // the pointer inside rdata, and continue elsewhere in the message, which is fine,
+    // names running past rdata fail to parse, and leftover bytes are rejected
+    let data = match type_ {
+        TYPE_PTR => {
+            let (rest, name) = parse_name(message, rdata)?;
+            all_consumed(rest)?;
+            RData::Ptr(name)
+        }
+        TYPE_SRV => {
+            let (rdata, priority) = be_u16(rdata)?;
+            let (rdata, weight) = be_u16(rdata)?;
+            let (rdata, port) = be_u16(rdata)?;
+            let (rest, target) = parse_name(message, rdata)?;
+            all_consumed(rest)?;
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            }
+        }
+        TYPE_TXT => RData::Txt(parse_txt(rdata)?.1),
+        TYPE_A if rdata.len() == 4 => RData::A(
+            <[u8; 4]>::try_from(rdata)
+                .expect("length is checked")
+                .into(),
+        ),
+        TYPE_AAAA if rdata.len() == 16 => RData::Aaaa(
+            <[u8; 16]>::try_from(rdata)
+                .expect("length is checked")
+                .into(),
+        ),
+        // Including addresses of invalid length
+        _ => RData::Other,
+    };
+    Ok((
+        input,
+        Record {
+            name,
+            type_,
+            class,
+            ttl,
+            data,
+        },
+    ))
+}
+
+pub(crate) fn parse_message(message: &[u8]) -> IResult<&[u8], Message> {
+    let (input, id) = be_u16(message)?;
+    let (input, flags) = be_u16(input)?;
+    let (input, questions) = be_u16(input)?;
+    let (input, answers) = be_u16(input)?;
+    let (input, authority) = be_u16(input)?;
+    let (mut input, additional) = be_u16(input)?;
+    for _ in 0..questions {
+        let (remaining, _name) = parse_name(message, input)?;
+        // Type and class
+        let (remaining, _) = take(4usize)(remaining)?;
+        input = remaining;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers as usize + authority as usize + additional as usize {
+        let (remaining, record) = parse_record(message, input)?;
+        records.push(record);
+        input = remaining;
+    }
+    Ok((input, Message { id, flags, records }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response to `_raop._tcp.local` PTR query, with SRV, TXT and addresses
+    /// in the additional section, names are compressed
+    const RESPONSE: &[u8] = b"\
+        \x12\x34\x84\x00\x00\x01\x00\x01\x00\x00\x00\x04\
+        \x05_raop\x04_tcp\x05local\x00\x00\x0c\x00\x01\
+        \xc0\x0c\x00\x0c\x00\x01\x00\x00\x11\x94\x00\x1b\x18001122334455@Living Room\xc0\x0c\
+        \xc0\x2e\x00\x21\x80\x01\x00\x00\x00\x78\x00\x14\x00\x00\x00\x00\x1b\x58\x0bLiving-Room\xc0\x17\
+        \xc0\x2e\x00\x10\x80\x01\x00\x00\x11\x94\x00\x29\x0acn=0,1,2,3\x08et=0,3,5\x14am=AudioAccessory5,1\
+        \xc0\x5b\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\xc0\xa8\x01\x14\
+        \xc0\x5b\x00\x1c\x80\x01\x00\x00\x00\x78\x00\x10\xfe\x80\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01";
+
+    const INSTANCE: &str = "001122334455@Living Room._raop._tcp.local";
+
+    #[test]
+    fn parse_response() {
+        let (rest, message) = parse_message(RESPONSE).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(message.id, 0x1234);
+        assert!(message.is_response());
+        assert!(!message.is_truncated());
+        assert_eq!(message.rcode(), 0);
+        let records = message.records;
+        assert_eq!(records.len(), 5);
+        assert!(records.iter().all(|r| r.class == CLASS_IN));
+
+        assert_eq!(records[0].name, "_raop._tcp.local");
+        assert_eq!(records[0].ttl, 4500);
+        assert!(matches!(&records[0].data, RData::Ptr(target) if target == INSTANCE));
+
+        assert_eq!(records[1].name, INSTANCE);
+        assert!(matches!(
+            &records[1].data,
+            RData::Srv { priority: 0, weight: 0, port: 7000, target } if target == "Living-Room.local"
+        ));
+        assert!(matches!(
+            &records[2].data,
+            RData::Txt(strings) if *strings == [&b"cn=0,1,2,3"[..], b"et=0,3,5", b"am=AudioAccessory5,1"]
+        ));
+        assert_eq!(records[3].name, "Living-Room.local");
+        assert!(matches!(records[3].data, RData::A(a) if a == Ipv4Addr::new(192, 168, 1, 20)));
+        assert!(
+            matches!(records[4].data, RData::Aaaa(a) if a == "fe80::1".parse::<Ipv6Addr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_truncated_response() {
+        for len in [0, 11, 20, 40, RESPONSE.len() - 1] {
+            assert!(parse_message(&RESPONSE[..len]).is_err(), "{len}");
+        }
+    }
+
+    /// `a._raop._tcp.local` PTR record with the given rdata, following
+    /// `_raop._tcp.local` name at offset 0
+    fn ptr_record(rd_length: u16, rdata: &[u8]) -> (Vec<u8>, usize) {
+        let mut message = b"\x05_raop\x04_tcp\x05local\x00".to_vec();
+        let start = message.len();
+        message.extend_from_slice(b"\xc0\x00\x00\x0c\x00\x01\x00\x00\x00\x78");
+        message.extend_from_slice(&rd_length.to_be_bytes());
+        message.extend_from_slice(rdata);
+        (message, start)
+    }
+
+    #[test]
+    fn parse_record_compressed_rdata() {
+        // Only the first label is inside rdata
+        let (message, start) = ptr_record(4, b"\x01a\xc0\x00");
+        let (rest, record) = parse_record(&message, &message[start..]).unwrap();
+        assert!(rest.is_empty());
+        assert!(matches!(record.data, RData::Ptr(target) if target == "a._raop._tcp.local"));
+        // SRV target, pointing into the owner name
+        let (rest, message) = parse_message(RESPONSE).unwrap();
+        assert!(rest.is_empty());
+        let srv = &message.records[1];
+        assert!(matches!(&srv.data, RData::Srv { target, .. } if target == "Living-Room.local"));
+    }
+
+    #[test]
+    fn parse_record_trailing_bytes() {
+        let (message, start) = ptr_record(5, b"\x01a\xc0\x00\x00");
+        assert_eq!(
+            error_kind(parse_record(&message, &message[start..])),
+            ErrorKind::LengthValue
+        );
+        let srv = b"\x01a\x00\x00\x21\x00\x01\x00\x00\x00\x78\x00\x0a\
+            \x00\x00\x00\x00\x1b\x58\x01b\x00\xff";
+        assert_eq!(error_kind(parse_record(srv, srv)), ErrorKind::LengthValue);
+    }
+
+    #[test]
+    fn parse_record_lying_length() {
+        // Name runs past rdata
+        let (message, start) = ptr_record(3, b"\x01a\xc0\x00");
+        assert_eq!(
+            error_kind(parse_record(&message, &message[start..])),
+            ErrorKind::Eof
+        );
+        // Rdata runs past the message
+        let (message, start) = ptr_record(16, b"\x01a\xc0\x00");
+        assert_eq!(
+            error_kind(parse_record(&message, &message[start..])),
+            ErrorKind::Eof
+        );
+    }
+
+    #[test]
+    fn parse_record_invalid_address() {
+        // A record with 3 bytes of rdata
+        let record = b"\x01a\x00\x00\x01\x00\x01\x00\x00\x00\x78\x00\x03\x01\x02\x03";
+        let (_, record) = parse_record(record, record).unwrap();
+        assert!(matches!(record.data, RData::Other));
+    }
+
+    fn error_kind<T: std::fmt::Debug>(result: IResult<&[u8], T>) -> ErrorKind {
+        match result.unwrap_err() {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.code,
+            e => panic!("unexpected error: {e:?}"),
+        }
+    }
+
+    /// `a` at offset 0, followed by `pointers` chained pointers to it,
+    /// returns message and offset of the last pointer
+    fn pointer_chain(pointers: usize) -> (Vec<u8>, usize) {
+        let mut message = b"\x01a\x00".to_vec();
+        let mut target = 0;
+        for _ in 0..pointers {
+            let offset = message.len();
+            message.extend_from_slice(&[0xc0, target as u8]);
+            target = offset;
+        }
+        (message, target)
+    }
+
+    #[test]
+    fn parse_name_pointer_loop() {
+        // Pointer to itself
+        let message = b"\xc0\x00";
+        assert_eq!(error_kind(parse_name(message, message)), ErrorKind::Verify);
+        // Two pointers to each other, after a label
+        let message = b"\x01a\xc0\x04\xc0\x02";
+        assert_eq!(error_kind(parse_name(message, message)), ErrorKind::Verify);
+        // Pointer past the end of the message
+        let message = b"\x01a\xc0\x10";
+        assert_eq!(error_kind(parse_name(message, message)), ErrorKind::Verify);
+    }
+
+    #[test]
+    fn parse_name_max_pointers() {
+        let (message, last) = pointer_chain(MAX_POINTERS);
+        let (rest, name) = parse_name(&message, &message[last..]).unwrap();
+        assert_eq!(name, "a");
+        // Name ends after the first pointer
+        assert!(rest.is_empty());
+
+        let (message, last) = pointer_chain(MAX_POINTERS + 1);
+        assert_eq!(
+            error_kind(parse_name(&message, &message[last..])),
+            ErrorKind::Verify
+        );
+    }
+
+    #[test]
+    fn encode_query_parses_back() {
+        let query = encode_query(7, FLAG_RD, "_raop._tcp.local.", TYPE_PTR).unwrap();
+        let (rest, message) = parse_message(&query).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(message.id, 7);
+        assert!(!message.is_response());
+        assert!(message.records.is_empty());
+        assert_eq!(&query[12..30], b"\x05_raop\x04_tcp\x05local\x00");
+        assert!(encode_query(7, FLAG_RD, &"a".repeat(64), TYPE_PTR).is_none());
+    }
+}
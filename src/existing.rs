@@ -0,0 +1,177 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use pipewire::{
+    registry::{Listener, Registry},
+    spa::ReadableDict,
+    types::ObjectType,
+    Context, Core, MainLoop, PW_ID_CORE,
+};
+use tracing::{debug, info};
+
+use crate::{Result, TunnelKey, KEY_PROP, MANAGED_PROP};
+
+/// Destroy sinks created by other (previous) instances of this daemon
+///
+/// Modules can't be unloaded from outside of the process owning them, but their
+/// nodes can be destroyed through the registry, which is enough to get rid of duplicates
+pub(crate) fn remove_existing(pw: &MainLoop, context: &Context<MainLoop>) -> Result<()> {
+    let core = context.connect(None)?;
+    let registry = core.get_registry()?;
+
+    let found = Rc::new(RefCell::new(Vec::new()));
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let found = found.clone();
+            move |global| {
+                if global.type_ != ObjectType::Node {
+                    return;
+                }
+                let Some(props) = &global.props else {
+                    return;
+                };
+                if props.get(MANAGED_PROP) == Some("true") {
+                    found.borrow_mut().push(global.id);
+                }
+            }
+        })
+        .register();
+
+    // Wait until all the existing globals are received
+    roundtrip(pw, &core)?;
+
+    for id in found.borrow().iter() {
+        info!("removing existing sink: {id}");
+        registry.destroy_global(*id);
+    }
+    // Make sure destroy requests are processed before disconnecting
+    roundtrip(pw, &core)?;
+    Ok(())
+}
+
+/// Tracks RAOP sink nodes in the graph, including ones created by other tools,
+/// to avoid creating duplicates
+pub(crate) struct ExistingSinks {
+    // Listener is alive while core/registry are, None if not watching the graph
+    _connection: Option<(Listener, Registry, Core)>,
+    /// Node id => (node.name, tunnel key if created by this daemon)
+    nodes: Rc<RefCell<HashMap<u32, (String, Option<String>)>>>,
+}
+
+impl ExistingSinks {
+    pub(crate) fn new(pw: &MainLoop, context: &Context<MainLoop>) -> Result<Self> {
+        let core = context.connect(None)?;
+        let registry = core.get_registry()?;
+        let nodes = Rc::new(RefCell::new(HashMap::new()));
+        let listener = registry
+            .add_listener_local()
+            .global({
+                let nodes = nodes.clone();
+                move |global| {
+                    if global.type_ != ObjectType::Node {
+                        return;
+                    }
+                    let Some(props) = &global.props else {
+                        return;
+                    };
+                    let Some(name) = props.get("node.name") else {
+                        return;
+                    };
+                    let key = props.get(KEY_PROP);
+                    if !name.starts_with("raop_sink.") && key.is_none() {
+                        return;
+                    }
+                    nodes
+                        .borrow_mut()
+                        .insert(global.id, (name.to_owned(), key.map(|k| k.to_owned())));
+                }
+            })
+            .global_remove({
+                let nodes = nodes.clone();
+                move |id| {
+                    nodes.borrow_mut().remove(&id);
+                }
+            })
+            .register();
+        // Populate with already existing nodes
+        roundtrip(pw, &core)?;
+        debug!("existing raop sinks: {:?}", nodes.borrow());
+        Ok(Self {
+            _connection: Some((listener, registry, core)),
+            nodes,
+        })
+    }
+
+    /// Not connected to pipewire, nodes are only added by `insert`
+    #[cfg(test)]
+    pub(crate) fn detached() -> Self {
+        Self {
+            _connection: None,
+            nodes: Rc::default(),
+        }
+    }
+
+    /// Node as reported by the registry
+    #[cfg(test)]
+    pub(crate) fn insert(&self, id: u32, name: &str, key: Option<&str>) {
+        self.nodes
+            .borrow_mut()
+            .insert(id, (name.to_owned(), key.map(|k| k.to_owned())));
+    }
+
+    /// Id of the node already existing for this tunnel.
+    ///
+    /// raop-sink names its nodes as `raop_sink.<hostname>.<ip>.<port>`
+    pub(crate) fn find(&self, key: &TunnelKey) -> Option<u32> {
+        let name = format!(
+            "raop_sink.{}.{}.{}",
+            key.hostname,
+            key.socket.ip(),
+            key.socket.port()
+        );
+        let key = key.to_string();
+        self.nodes
+            .borrow()
+            .iter()
+            .find(|(_, (n, k))| *n == name || k.as_deref() == Some(key.as_str()))
+            .map(|(id, _)| *id)
+    }
+
+    /// Tunnel was unloaded, its nodes are forgotten right away, as the registry
+    /// reports their removal only later, and the tunnel might be recreated before that
+    ///
+    /// Only nodes tagged with the key are forgotten, nodes of other tools are still
+    /// in the graph
+    pub(crate) fn unloaded(&self, key: &TunnelKey) {
+        let key = key.to_string();
+        self.nodes
+            .borrow_mut()
+            .retain(|_, (_, k)| k.as_deref() != Some(key.as_str()));
+    }
+}
+
+fn roundtrip(pw: &MainLoop, core: &Core) -> Result<()> {
+    let done = Rc::new(Cell::new(false));
+    let pending = core.sync(0)?;
+    let _listener = core
+        .add_listener_local()
+        .done({
+            let done = done.clone();
+            let pw = pw.clone();
+            move |id, seq| {
+                if id == PW_ID_CORE && seq == pending {
+                    done.set(true);
+                    pw.quit();
+                }
+            }
+        })
+        .register();
+    while !done.get() {
+        pw.run();
+    }
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use tracing::{debug, warn};
+
+use crate::TunnelKey;
+
+/// Repeated identical failure is summarized every this many occurrences
+const SUMMARY_INTERVAL: u32 = 20;
+
+struct Failure {
+    reason: String,
+    count: u32,
+}
+
+/// Last failure of every device, so the same failure repeated every scan
+/// doesn't flood the logs
+#[derive(Default)]
+pub(crate) struct Failures(RefCell<HashMap<TunnelKey, Failure>>);
+
+impl Failures {
+    /// New failure is logged as warning, repeated ones at debug level, with a
+    /// periodic summary
+    pub(crate) fn report(&self, key: &TunnelKey, reason: String) {
+        let mut failures = self.0.borrow_mut();
+        match failures.get_mut(key) {
+            Some(failure) if failure.reason == reason => {
+                failure.count += 1;
+                if failure.count % SUMMARY_INTERVAL == 0 {
+                    warn!(
+                        "tunnel {key:?} has failed {} times: {reason}",
+                        failure.count
+                    );
+                } else {
+                    debug!("tunnel {key:?} failed again: {reason}");
+                }
+            }
+            _ => {
+                warn!("tunnel {key:?} failed: {reason}");
+                failures.insert(key.clone(), Failure { reason, count: 1 });
+            }
+        }
+    }
+
+    /// Tunnel was loaded
+    pub(crate) fn clear(&self, key: &TunnelKey) {
+        self.0.borrow_mut().remove(key);
+    }
+
+    /// Last failure of every device, with the number of times it was repeated
+    pub(crate) fn summaries(&self) -> HashMap<TunnelKey, (String, u32)> {
+        self.0
+            .borrow()
+            .iter()
+            .map(|(key, f)| (key.clone(), (f.reason.clone(), f.count)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(failures: &Failures, key: &TunnelKey) -> Option<u32> {
+        failures.0.borrow().get(key).map(|f| f.count)
+    }
+
+    #[test]
+    fn repeated_failures() {
+        let failures = Failures::default();
+        let key = TunnelKey {
+            hostname: "a.local".to_owned(),
+            socket: "192.168.1.2:7000".parse().unwrap(),
+        };
+        failures.report(&key, "busy".to_owned());
+        failures.report(&key, "busy".to_owned());
+        assert_eq!(count(&failures, &key), Some(2));
+        // Other reason starts counting again
+        failures.report(&key, "unreachable".to_owned());
+        assert_eq!(count(&failures, &key), Some(1));
+        assert_eq!(failures.summaries()[&key], ("unreachable".to_owned(), 1));
+        failures.clear(&key);
+        assert_eq!(count(&failures, &key), None);
+    }
+}
@@ -0,0 +1,85 @@
+use std::{fmt, io, os::unix::net::UnixDatagram};
+
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends events to journald using its native protocol, event fields are
+/// passed as structured journal fields, i.e `error` field becomes `ERROR=`
+///
+/// Events too large for a single datagram are dropped
+pub(crate) struct JournaldLayer {
+    socket: UnixDatagram,
+}
+
+impl JournaldLayer {
+    pub(crate) fn new() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SOCKET)?;
+        Ok(Self { socket })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JournaldLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut buf = Vec::new();
+        put_field(&mut buf, "PRIORITY", priority(metadata.level()));
+        put_field(&mut buf, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+        put_field(&mut buf, "TARGET", metadata.target());
+        event.record(&mut Fields(&mut buf));
+        // Journal might be restarting, there is nowhere else to report it
+        let _ = self.socket.send(&buf);
+    }
+}
+
+/// syslog(3) priority
+fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Values containing newlines are written in binary form, prefixed with length
+fn put_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        // Journal field names are uppercase, and can't start with underscore,
+        // which is reserved for trusted fields
+        let name: String = field
+            .name()
+            .trim_start_matches('_')
+            .chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' | '0'..='9' => c,
+                _ => '_',
+            })
+            .collect();
+        put_field(self.0, &name, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"))
+    }
+}
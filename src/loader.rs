@@ -0,0 +1,89 @@
+use std::{ffi::CString, ptr::NonNull, rc::Rc};
+
+use pipewire::{spa::WritableDict, Context, MainLoop, Properties};
+use pipewire_sys::pw_impl_module;
+
+use tracing::info;
+
+use crate::{serialize::serialize_properties, Error, Result};
+
+/// Loads modules for tunnels, so tunnel management doesn't depend on a running pipewire
+pub(crate) trait ModuleLoader {
+    /// Load module with `args`. `tags` are set on the module, and on the nodes it creates
+    fn load(&self, args: Properties, tags: Properties) -> Result<NonNull<pw_impl_module>>;
+    fn unload(&self, module: NonNull<pw_impl_module>);
+}
+
+/// Loaded module, which is unloaded on drop
+///
+/// Handle is `!Send` (module pointer isn't), so that only may happen on the
+/// pipewire main loop thread
+pub(crate) struct ModuleHandle {
+    module: NonNull<pw_impl_module>,
+    loader: Rc<dyn ModuleLoader>,
+}
+
+impl ModuleHandle {
+    pub(crate) fn load(
+        loader: &Rc<dyn ModuleLoader>,
+        args: Properties,
+        tags: Properties,
+    ) -> Result<Self> {
+        Ok(Self {
+            module: loader.load(args, tags)?,
+            loader: loader.clone(),
+        })
+    }
+}
+
+impl Drop for ModuleHandle {
+    fn drop(&mut self) {
+        self.loader.unload(self.module);
+    }
+}
+
+/// Loads modules into the local pipewire context
+pub(crate) struct PipewireLoader {
+    pub context: Rc<Context<MainLoop>>,
+    /// Module name, i.e `libpipewire-module-raop-sink`
+    pub module: CString,
+}
+
+impl ModuleLoader for PipewireLoader {
+    fn load(&self, mut args: Properties, tags: Properties) -> Result<NonNull<pw_impl_module>> {
+        let stream_props = serialize_properties(&tags)?;
+        args.insert("stream.props", stream_props);
+        let args = serialize_properties(&args)?;
+        let module = unsafe {
+            pipewire_sys::pw_context_load_module(
+                self.context.as_ptr(),
+                self.module.as_ptr(),
+                args.as_ptr(),
+                // Module takes ownership
+                tags.into_raw(),
+            )
+        };
+        NonNull::new(module).ok_or(Error::ModuleLoad)
+    }
+
+    fn unload(&self, module: NonNull<pw_impl_module>) {
+        unsafe { pipewire_sys::pw_impl_module_destroy(module.as_ptr()) };
+    }
+}
+
+/// Only logs what would be loaded, without touching the graph
+pub(crate) struct DryRunLoader;
+
+impl ModuleLoader for DryRunLoader {
+    fn load(&self, args: Properties, tags: Properties) -> Result<NonNull<pw_impl_module>> {
+        info!(
+            "would load module with {}, tags {}",
+            serialize_properties(&args)?.to_string_lossy(),
+            serialize_properties(&tags)?.to_string_lossy(),
+        );
+        // Never dereferenced, as unload does nothing
+        Ok(NonNull::dangling())
+    }
+
+    fn unload(&self, _module: NonNull<pw_impl_module>) {}
+}
@@ -0,0 +1,52 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Log file, rotated when it would grow over `max_size` bytes, previous
+/// file is kept with `.1` suffix
+pub(crate) struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// 0 to never rotate
+    max_size: u64,
+}
+
+impl LogFile {
+    pub(crate) fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        *self = Self::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_size != 0 && self.size != 0 && self.size + buf.len() as u64 > self.max_size {
+            // There is nowhere to report the error, keep writing to the current file
+            let _ = self.rotate();
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
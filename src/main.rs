@@ -1,87 +1,317 @@
-#![feature(ip)]
-
 use std::{
-    cell::RefCell,
-    collections::{BTreeSet, HashMap},
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-    ptr::null_mut,
+    cell::{Cell, RefCell},
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    rc::Rc,
     result,
-    sync::mpsc::{self, Receiver},
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
-use dbus::blocking::SyncConnection;
+use clap::Parser;
 use derivative::Derivative;
-use libc::{fclose, fprintf, free, open_memstream};
+use nom::error::ErrorKind;
 use pipewire::{
-    properties,
-    spa::{ReadableDict, WritableDict},
-    Context,
+    channel::{self, Receiver, Sender},
+    Context, MainLoop,
 };
-use pipewire_sys::pw_impl_module;
-use real_c_string::real_c_string;
+use tracing::{debug, error, info, trace, warn, Level};
+use tracing_subscriber::{filter::LevelFilter, fmt::writer::BoxMakeWriter, EnvFilter};
 
+#[cfg(feature = "journald")]
+use crate::journald::JournaldLayer;
 use crate::{
-    resolve1::OrgFreedesktopResolve1Manager,
-    rr::{parse_name, parse_rr},
+    addr::{socket_addr, FamilyPreference},
+    airplay::{device_id, device_name, instance_name, AirplayService, AudioFlags, Codec, Metadata},
+    backend::RaopBackend,
+    config::Config,
+    control::{default_socket, send, serve, Command},
+    cooldown::{Backoff, Cooldown},
+    daemon::daemonize,
+    existing::{remove_existing, ExistingSinks},
+    failures::Failures,
+    loader::{DryRunLoader, ModuleHandle, ModuleLoader, PipewireLoader},
+    logfile::LogFile,
+    notify::notify,
+    opts::{LogTarget, Opts},
+    ratelimit::warn_limited,
+    resolver::{is_negative, ResolveFlags, Resolver},
+    rr::{
+        hex, parse_rdata_name, parse_rr, select_srv, split_class, strip_name_suffix, unescape_name,
+    },
+    signal::{refresh_requested, reload_requested, shutdown_requested, watch_signals},
+    trigger::ScanTrigger,
+    tunnels::{unload_tunnels, Daemon},
+    txt::Txt,
+    unicast::unicast_discovery,
 };
+#[cfg(feature = "builtin-mdns")]
+use crate::{mdns::builtin_discovery, opts::DiscoveryBackend};
+#[cfg(feature = "journald")]
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+mod addr;
+mod airplay;
+mod backend;
+mod config;
+mod control;
+mod cooldown;
+mod daemon;
+mod dns;
+mod existing;
+mod failures;
+#[cfg(feature = "journald")]
+mod journald;
+mod loader;
+mod logfile;
+#[cfg(feature = "builtin-mdns")]
+mod mdns;
+mod notify;
+mod opts;
+mod pattern;
+mod properties;
+mod ratelimit;
 mod resolve1;
+mod resolver;
 mod rr;
+mod serialize;
+mod signal;
+mod template;
+#[cfg(test)]
+mod testing;
+mod trigger;
+mod tunnels;
+mod txt;
+mod unicast;
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error("dbus: {0}")]
-    Dbus(#[from] dbus::Error),
-    #[error("parsing: {0}")]
-    Nom(String),
+    Dbus(dbus::Error),
+    #[error("dbus call timed out, resolver is too slow (see --dbus-timeout): {0}")]
+    DbusTimeout(dbus::Error),
+    #[error("parsing {input} failed at offset {offset}: {kind:?}")]
+    Nom {
+        input: &'static str,
+        offset: usize,
+        kind: ErrorKind,
+    },
     #[error("pipewire: {0}")]
     Pipewire(#[from] pipewire::Error),
     #[error("spa: {0}")]
     Spa(#[from] pipewire::spa::Error),
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("config: {0}")]
+    Config(#[from] toml::de::Error),
+    #[error("properties serialization: {0}")]
+    Serialization(String),
+    #[error("module failed to load, see pipewire logs")]
+    ModuleLoad,
+    #[error("dns: {0}")]
+    Dns(String),
+    #[error("event receiver is dead")]
+    ReceiverDead,
 }
-impl From<nom::Err<nom::error::Error<&[u8]>>> for Error {
-    fn from(value: nom::Err<nom::error::Error<&[u8]>>) -> Self {
-        Self::Nom(value.to_string())
+impl From<dbus::Error> for Error {
+    fn from(value: dbus::Error) -> Self {
+        match value.name() {
+            Some("org.freedesktop.DBus.Error.NoReply" | "org.freedesktop.DBus.Error.Timeout") => {
+                Self::DbusTimeout(value)
+            }
+            _ => Self::Dbus(value),
+        }
+    }
+}
+impl Error {
+    /// Parse error of the named `input`, with offset where parsing failed
+    fn nom(input: &'static str, data: &[u8], err: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        let (offset, kind) = match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => (data.len() - e.input.len(), e.code),
+            // Only complete parsers are used
+            nom::Err::Incomplete(_) => (data.len(), ErrorKind::Eof),
+        };
+        // Bytes are needed to report misbehaving devices
+        debug!(
+            "{input} failed to parse at {offset}: {}{}",
+            hex(&data[..data.len().min(PARSE_DUMP_LIMIT)]),
+            if data.len() > PARSE_DUMP_LIMIT {
+                "..."
+            } else {
+                ""
+            }
+        );
+        Self::Nom {
+            input,
+            offset,
+            kind,
+        }
     }
 }
 type Result<T, E = Error> = result::Result<T, E>;
 
 const DEST: &str = "org.freedesktop.resolve1";
 const PATH: &str = "/org/freedesktop/resolve1";
-const RECORD: &str = "_raop._tcp.local";
+const SERVICE: &str = "_raop._tcp";
+/// Companion service of AirPlay devices, only used for metadata
+const AIRPLAY_SERVICE: &str = "_airplay._tcp";
+
+/// Set on every created module and its sink node, so they can be found later
+const MANAGED_PROP: &str = "pw-resolved-discover.managed";
+/// `<hostname>:<socket>` of the tunnel
+const KEY_PROP: &str = "pw-resolved-discover.key";
+/// Canonical service instance name of the tunnel, if it was discovered
+const SERVICE_PROP: &str = "pw-resolved-discover.service";
 
 const IFINDEX_ANY: i32 = 0;
 
+/// How many scans host might be missing from, before it is considered removed
+const REMOVAL_RETRIES: u32 = 8;
+/// Removals are tracked after this many scans, even if discovery didn't settle
+const BASELINE_MAX_SCANS: u32 = 20;
+
+/// How many bytes of data which failed to parse are logged
+const PARSE_DUMP_LIMIT: usize = 512;
+
+/// Delay between scans
+const SCAN_INTERVAL: Duration = Duration::from_secs(3);
+/// How often SIGINT/SIGTERM is checked for while waiting to retry pipewire initialization
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
 const CLASS_IN: u16 = 1;
 const TYPE_PTR: u16 = 12;
 
-const MDNS_V4: u64 = 8;
-const MDNS_V6: u64 = 16;
-
+const AF_UNSPEC: i32 = 0;
 const AF_INET4: i32 = 2;
 const AF_INET6: i32 = 10;
 
-#[derive(Hash, PartialEq, Eq, Debug)]
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
 struct TunnelKey {
     hostname: String,
     socket: SocketAddr,
 }
+impl fmt::Display for TunnelKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.hostname, self.socket)
+    }
+}
 struct Tunnel {
-    module: *mut pw_impl_module,
+    /// Service instance tunnel was created for, None for static devices
+    domain: Option<String>,
+    /// Tunnel is kept even after device disappears
+    pinned: bool,
+    /// `gid=` of the device group
+    group: Option<String>,
+    /// Sink name, unique among tunnels, None if device is unsupported
+    name: Option<String>,
+    /// Interface device was discovered on, 0 if unknown
+    ifindex: i32,
+    /// `vs=` as advertised
+    version: Option<String>,
+    /// None if `md=` isn't advertised
+    metadata: Option<Metadata>,
+    audio: AudioFlags,
+    state: TunnelState,
+}
+enum TunnelState {
+    /// Module is being loaded, tunnel shouldn't be attempted again
+    Loading,
+    /// Module is unloaded when tunnel is dropped
+    Loaded(#[allow(unused)] ModuleHandle),
+    /// Module is loaded, but its sink didn't appear in the graph yet, with `--connect-timeout`
+    Connecting(ModuleHandle, Instant),
+    /// Module failed to load, will be retried on the next scan
+    Failed,
+    /// Device can't be handled by the module, won't be retried until it reappears
+    Unsupported,
+}
+
+enum Event {
+    Discovered(Discovered),
+    /// Service instance is no longer advertised
+    Removed(String),
 }
 
 struct Discovered {
     hostname: String,
     socket: SocketAddr,
-    records: Vec<String>,
+    /// Interface address was resolved on
+    ifindex: i32,
+    /// Service instance name, None for static devices
+    domain: Option<String>,
+    /// Name from the service instance label
+    instance: Option<String>,
+    /// `<name>.<type>.<domain>` returned by resolver, which might differ from the
+    /// requested domain, if it was an alias. None for static devices
+    canonical: Option<String>,
+    txt: Txt,
+}
+
+impl Discovered {
+    fn key(&self) -> TunnelKey {
+        TunnelKey {
+            hostname: self.hostname.clone(),
+            socket: self.socket,
+        }
+    }
+
+    /// Value of TXT record with this key
+    fn txt(&self, key: &str) -> Option<&str> {
+        self.txt.get(key)
+    }
+
+    /// First of the fields required for tunnel to work which is missing,
+    /// advertisement might be incomplete yet
+    fn missing_field(&self) -> Option<&'static str> {
+        if self.socket.ip().is_unspecified() {
+            Some("address")
+        } else if self.socket.port() == 0 {
+            Some("port")
+        } else if self.txt("cn").and_then(Codec::from_cn).is_none() {
+            Some("codec (cn=)")
+        } else {
+            None
+        }
+    }
+
+    /// `am=` TXT record
+    fn model(&self) -> Option<&str> {
+        self.txt("am")
+    }
+
+    /// Instance name, or model if unknown
+    fn name(&self) -> Option<String> {
+        self.instance
+            .clone()
+            .or_else(|| self.model().map(|v| v.to_owned()))
+    }
+
+    /// Name, or `unnamed` followed by the hostname, so unnamed devices are still
+    /// distinguishable
+    fn readable_name(&self, unnamed: &str) -> String {
+        self.name()
+            .unwrap_or_else(|| format!("{unnamed} ({})", self.hostname))
+    }
 }
 
+/// Errors are rate limited, as the same one is likely to happen on every scan
 macro_rules! try_continue {
     ($v:expr) => {
         match $v {
             Ok(r) => r,
             Err(e) => {
-                eprintln!("{e}");
+                warn_limited(e.to_string());
+                continue;
+            }
+        }
+    };
+    ($v:expr, $device:expr) => {
+        match $v {
+            Ok(r) => r,
+            Err(e) => {
+                warn_limited(format!("{}: {e}", $device));
                 continue;
             }
         }
@@ -98,277 +328,977 @@ struct ResolvedHost {
     retries: u32,
 }
 
-fn found_mdns() {
-    let connection = SyncConnection::new_system().expect("system connection failed");
+/// Compare hosts found during this scan with the previous scan, returning
+/// the new set of known hosts, and hosts that were added/removed
+///
+/// Missing hosts are kept until their retries are exhausted, so a single empty scan
+/// (which resolve1 might return after cache flush) doesn't remove anything, while hosts
+/// missing from REMOVAL_RETRIES + 1 consecutive scans are removed. Failed scans
+/// shouldn't be reconciled at all, as they say nothing about the hosts
+///
+/// Until `baseline` is established, missing hosts keep their retries, as responses
+/// are still trickling in after startup, and hosts missing from some scans aren't gone
+fn reconcile(
+    previous: &BTreeSet<ResolvedHost>,
+    mut current: BTreeSet<ResolvedHost>,
+    baseline: bool,
+) -> (BTreeSet<ResolvedHost>, Vec<ResolvedHost>, Vec<ResolvedHost>) {
+    let mut readd = Vec::new();
+    let mut removed = Vec::new();
+    for missing in previous.difference(&current) {
+        if !baseline {
+            readd.push(missing.clone());
+        } else if missing.retries == 0 {
+            removed.push(missing.clone());
+        } else {
+            // Give host some time before finally removing it
+            // in case of mdns cache flushes et cetera
+            let mut missing = missing.clone();
+            missing.retries -= 1;
+            readd.push(missing);
+        }
+    }
+    current.extend(readd);
+    let added = current.difference(previous).cloned().collect();
+    (current, added, removed)
+}
+
+/// Hosts known from the previous scans
+#[derive(Default)]
+struct KnownHosts {
+    hosts: BTreeSet<ResolvedHost>,
+    /// Established by the first non-empty scan which doesn't find new hosts, empty
+    /// scans might be caused by resolve1 cache flush right after startup
+    baseline: bool,
+    scans: u32,
+}
+
+impl KnownHosts {
+    /// Reconcile hosts found during successful scan, returning added and removed hosts
+    fn update(
+        &mut self,
+        current: BTreeSet<ResolvedHost>,
+    ) -> (Vec<ResolvedHost>, Vec<ResolvedHost>) {
+        let found = !current.is_empty();
+        let (hosts, added, removed) = reconcile(&self.hosts, current, self.baseline);
+        self.scans += 1;
+        if !self.baseline && (found && added.is_empty() || self.scans >= BASELINE_MAX_SCANS) {
+            debug!(
+                "baseline of {} hosts established after {} scans, tracking removals",
+                hosts.len(),
+                self.scans
+            );
+            self.baseline = true;
+        }
+        self.hosts = hosts;
+        (added, removed)
+    }
+}
+
+/// Records in `.local` are resolved using mDNS, everything else is unicast DNS-SD
+fn is_mdns_domain(domain: &str) -> bool {
+    domain.trim_end_matches('.').eq_ignore_ascii_case("local")
+}
+
+fn found_mdns(
+    opts: &Opts,
+    extra_flags: ResolveFlags,
+    resolver: Resolver,
+    tx: Sender<Event>,
+    trigger: ScanTrigger,
+) {
+    let record = format!("{SERVICE}.{}", opts.domain);
+    let flags = if is_mdns_domain(&opts.domain) {
+        ResolveFlags::MDNS_V4 | ResolveFlags::MDNS_V6
+    } else {
+        ResolveFlags::DNS
+    } | opts.cache_flags()
+        | extra_flags;
     std::thread::spawn(move || {
-        let proxy = connection.with_proxy(DEST, PATH, Duration::from_millis(2000));
-        let mut resolved = BTreeSet::new();
+        let mut known = KnownHosts::default();
+        let mut triggered = 0;
         loop {
             let mut resolved_this_time = BTreeSet::new();
-            let (records, _flags) = try_continue!(proxy.resolve_record(
-                IFINDEX_ANY,
-                RECORD,
-                CLASS_IN,
-                TYPE_PTR,
-                MDNS_V4 | MDNS_V6
-            ));
+            let records = try_continue!(resolver.resolve_ptr(&record, flags).map_err(Error::from));
+            if records.is_empty() && !known.hosts.is_empty() {
+                debug!(
+                    "no hosts found, keeping {} known hosts until retries are exhausted",
+                    known.hosts.len()
+                );
+            }
             for record in records {
                 let (ifindex, class, type_, data) = record;
-                if class != CLASS_IN || type_ != TYPE_PTR {
-                    eprintln!("unexpected class/type record");
+                if split_class(class).0 != CLASS_IN || type_ != TYPE_PTR {
+                    warn!("unexpected class/type record");
                     continue;
                 }
-                let (_rest, rr) = try_continue!(parse_rr(&data));
+                let (_rest, rr) =
+                    try_continue!(parse_rr(&data).map_err(|e| Error::nom("record", &data, e)));
                 if rr.class != CLASS_IN || rr.type_ != TYPE_PTR {
-                    eprintln!("unexpected class/type rr");
+                    warn!("unexpected class/type rr");
                     continue;
                 }
-                let (_rest, domain) = try_continue!(parse_name(&rr.rdata));
+                let (_rest, domain) = try_continue!(
+                    parse_rdata_name(&rr.rdata).map_err(|e| Error::nom("ptr rdata", &rr.rdata, e))
+                );
                 resolved_this_time.insert(ResolvedHost {
                     ifindex,
                     name: rr.name,
                     domain,
-                    retries: 8,
+                    retries: REMOVAL_RETRIES,
                 });
             }
-            let mut readd = Vec::new();
-            for removed in resolved.difference(&resolved_this_time) {
-                if removed.retries == 0 {
-                    eprintln!("removed host: {removed:?}")
-                } else {
-                    // Give host some time before finally removing it
-                    // in case of mdns cache flushes et cetera
-                    let mut removed = removed.clone();
-                    removed.retries -= 1;
-                    readd.push(removed);
+            let (added, removed) = known.update(resolved_this_time);
+            for removed in removed {
+                info!("removed host: {removed:?}");
+                // Same instance might still be visible on other interface
+                if known.hosts.iter().any(|h| h.domain == removed.domain) {
+                    continue;
+                }
+                if tx.send(Event::Removed(removed.domain)).is_err() {
+                    error!("receiver is dead");
+                    return;
                 }
             }
-            resolved_this_time.extend(readd);
-            for added in resolved_this_time.difference(&resolved) {
-                eprintln!("added host: {added:?}")
+            for added in added {
+                info!("added host: {added:?}")
             }
-            resolved = resolved_this_time;
-            std::thread::sleep(Duration::from_secs(3));
+            trigger.wait(&mut triggered, SCAN_INTERVAL);
         }
     });
 }
 
-fn resolved_mdns() -> Receiver<Discovered> {
-    found_mdns();
-    let (tx, rx) = mpsc::channel();
-    let connection = SyncConnection::new_system().expect("system connection failed");
+/// Unique service instances pointed to by PTR records
+///
+/// Same instance might be announced by multiple responders, or be visible on
+/// multiple interfaces, it only needs to be resolved once. Names are case-insensitive,
+/// spelling of the first record is kept
+fn ptr_targets(records: Vec<(i32, u16, u16, Vec<u8>)>, service: &str) -> BTreeSet<String> {
+    let mut domains = BTreeMap::new();
+    for record in records {
+        let (ifindex, class, type_, data) = record;
+        trace!(
+            "record: ifindex={ifindex} class={class} type={type_} data={}",
+            hex(&data)
+        );
+        let (_rest, rr) =
+            try_continue!(parse_rr(&data).map_err(|e| Error::nom("record", &data, e)));
+        if type_ != TYPE_PTR || rr.type_ != TYPE_PTR {
+            warn!("received non-ptr record on ptr request");
+            continue;
+        }
+        let (_rest, domain) = try_continue!(parse_rdata_name(&rr.rdata).map_err(|e| Error::nom(
+            "ptr rdata",
+            &rr.rdata,
+            e
+        )));
+        if strip_name_suffix(&domain, service).is_none() {
+            warn_limited(format!(
+                "ptr target {domain} is not an instance of {service}"
+            ));
+            continue;
+        }
+        let key = domain.to_ascii_lowercase();
+        if domains.contains_key(&key) {
+            debug!("duplicate ptr target: {domain}");
+            continue;
+        }
+        domains.insert(key, domain);
+    }
+    domains.into_values().collect()
+}
+
+/// `_airplay._tcp` services by their device id
+fn airplay_services(
+    resolver: &Resolver,
+    service: &str,
+    flags: ResolveFlags,
+    service_flags: ResolveFlags,
+) -> Result<HashMap<String, AirplayService>> {
+    let records = resolver.resolve_ptr(service, flags)?;
+    let domains: Vec<_> = ptr_targets(records, service).into_iter().collect();
+    let replies = resolver.resolve_services(&domains, AF_UNSPEC, service_flags);
+    let mut services = HashMap::new();
+    for (domain, reply) in domains.into_iter().zip(replies) {
+        let (_srvs, records, canonical_name, _type, _domain, _flags) =
+            try_continue!(reply.map_err(Error::from), domain);
+        let txt = Txt::new(
+            records
+                .into_iter()
+                .map(|r| String::from_utf8_lossy(&r).replace('\0', ""))
+                .collect(),
+        );
+        let Some(id) = txt.get("deviceid").and_then(device_id) else {
+            debug!("{domain} doesn't advertise deviceid, can't correlate it");
+            continue;
+        };
+        services.insert(
+            id,
+            AirplayService {
+                name: device_name(&canonical_name),
+                txt,
+            },
+        );
+    }
+    Ok(services)
+}
+
+/// `last_scan` is updated after every successful scan
+fn resolved_mdns(
+    opts: &Opts,
+    extra_flags: ResolveFlags,
+    last_scan: Arc<Mutex<Instant>>,
+    trigger: ScanTrigger,
+) -> Receiver<Event> {
+    let (tx, rx) = channel::channel();
+    let family = opts.family;
+    let timeout = Duration::from_millis(opts.dbus_timeout);
+    let empty_scans_warning = opts.empty_scans_warning;
+    let max_resolves = opts.max_resolves;
+    let service = format!("{SERVICE}.{}", opts.domain);
+    let flags = if is_mdns_domain(&opts.domain) {
+        ResolveFlags::MDNS_V4 // | ResolveFlags::MDNS_V6
+    } else {
+        ResolveFlags::DNS
+    } | opts.cache_flags()
+        | extra_flags;
+    let service_flags = opts.cache_flags() | extra_flags;
+    let airplay_service = opts
+        .airplay
+        .then(|| format!("{AIRPLAY_SERVICE}.{}", opts.domain));
+    let zone = opts.interface.as_ref().map(|i| {
+        info!("discovering on {} (index {})", i.name, i.index);
+        i.index
+    });
+    let resolver = Resolver::new(
+        opts.dbus_address.as_deref(),
+        timeout,
+        zone.unwrap_or(IFINDEX_ANY),
+    )
+    .expect("system connection failed")
+    .with_service(opts.resolve1_dest.clone(), opts.resolve1_path.clone())
+    .with_link_fallback(opts.link_fallback);
+    found_mdns(
+        opts,
+        extra_flags,
+        resolver.clone(),
+        tx.clone(),
+        trigger.clone(),
+    );
     std::thread::spawn(move || {
-        // FIXME: Ipv6 doesn't work, RAOP sink doesn't supports link-local addresses
-        // TODO: Should be raop.ip.scope_id be added to pipewire module?
-        let v4 = true;
-        let proxy = connection.with_proxy(DEST, PATH, Duration::from_millis(2000));
+        let mut empty_scans = 0;
+        // Instances which resolved reported as nonexistent, removed once
+        let mut gone = HashSet::new();
+        // Instance to start resolving from, when limited by --max-resolves
+        let mut next_resolve = 0;
+        // Instances without addresses of allowed family or with port 0, logged once
+        let mut skipped = HashSet::new();
+        let mut triggered = 0;
         loop {
-            eprintln!("scanning, ipv4 = {v4}");
-            let (records, flags) = try_continue!(proxy.resolve_record(
-                IFINDEX_ANY,
-                RECORD,
-                CLASS_IN,
-                TYPE_PTR,
-                MDNS_V4 // | MDNS_V6
-            ));
-            // v4 = !v4;
-            for record in records {
-                let (_ifindex, _class, type_, data) = record;
-                let (_rest, rr) = try_continue!(parse_rr(&data));
-                if type_ != TYPE_PTR || rr.type_ != TYPE_PTR {
-                    eprintln!("received non-ptr record on ptr request");
-                    continue;
+            debug!(
+                "scanning, family = {family:?}, last successful scan {:?} ago",
+                last_scan.lock().expect("not poisoned").elapsed()
+            );
+            let records = try_continue!(resolver.resolve_ptr(&service, flags).map_err(Error::from));
+            if records.is_empty() {
+                empty_scans += 1;
+                if empty_scans == empty_scans_warning {
+                    warn!(
+                        "no {service} devices found after {empty_scans} scans, check that mDNS is enabled in resolved and UDP 5353 isn't blocked"
+                    );
                 }
-                let (_rest, domain) = try_continue!(parse_name(&rr.rdata));
-                let (srvs, records, _name, _service, _domain, _idk) = try_continue!(proxy
-                    .resolve_service(
-                        IFINDEX_ANY,
-                        "",
-                        "",
-                        &domain,
-                        if v4 { AF_INET4 } else { AF_INET6 },
-                        0,
-                    ));
-
-                let records: Vec<_> = records
-                    .into_iter()
-                    .map(|r| String::from_utf8_lossy(&r).to_string())
-                    .collect();
-
-                for srv in srvs {
-                    let (priority, weight, port, hostname, ips, domain) = srv;
-                    for ip in ips {
-                        let (ifindex, af, address) = ip;
-                        let socket: SocketAddr = if af == AF_INET6 && address.len() == 16 {
-                            let mut addr = [0; 16];
-                            addr.copy_from_slice(&address);
-                            let addr = Ipv6Addr::from(addr);
-                            SocketAddrV6::new(
-                                addr,
-                                port,
-                                0,
-                                if addr.is_unicast_link_local() {
-                                    ifindex as u32
-                                } else {
-                                    0
-                                },
-                            )
-                            .into()
-                            // SocketAddrV6::new(, port)
-                        } else if af == AF_INET4 && address.len() == 4 {
-                            let mut addr = [0; 4];
-                            addr.copy_from_slice(&address);
-                            SocketAddrV4::new(Ipv4Addr::from(addr), port).into()
-                        } else {
-                            eprintln!("unknown address family: {af} {address:?}");
+            } else {
+                empty_scans = 0;
+            }
+            let airplay = match &airplay_service {
+                Some(service) => airplay_services(&resolver, service, flags, service_flags)
+                    .unwrap_or_else(|e| {
+                        warn!("failed to discover {service}: {e}");
+                        HashMap::new()
+                    }),
+                None => HashMap::new(),
+            };
+            // Aliases of already resolved instances
+            let mut seen = HashSet::new();
+            let mut domains: Vec<_> = ptr_targets(records, &service).into_iter().collect();
+            if max_resolves != 0 && domains.len() > max_resolves {
+                // Crowded network, only a window of instances is resolved per scan,
+                // so the scan doesn't take too long
+                let start = next_resolve % domains.len();
+                domains.rotate_left(start);
+                domains.truncate(max_resolves);
+                next_resolve = start + max_resolves;
+                debug!("resolving {max_resolves} instances starting from {start}");
+            }
+            let replies = resolver.resolve_services(&domains, AF_UNSPEC, service_flags);
+            for (domain, reply) in domains.into_iter().zip(replies) {
+                let (srvs, records, canonical_name, canonical_type, canonical_domain, flags) =
+                    match reply {
+                        Ok(reply) => reply,
+                        Err(e) if is_negative(&e) => {
+                            // PTR record might still be cached, while the instance is already gone
+                            if gone.insert(domain.clone()) {
+                                info!("{domain} is confirmed gone: {e}, removing");
+                                if tx.send(Event::Removed(domain)).is_err() {
+                                    error!("receiver is dead");
+                                    return;
+                                }
+                            }
                             continue;
-                        };
-
-                        if tx
-                            .send(Discovered {
-                                hostname: hostname.clone(),
-                                socket,
-                                records: records.clone(),
-                            })
-                            .is_err()
-                        {
-                            eprintln!("receiver is dead");
-                            return;
                         }
+                        Err(e) => {
+                            warn_limited(format!("{domain} is unresolvable for now: {e}"));
+                            continue;
+                        }
+                    };
+                gone.remove(&domain);
+                if tracing::enabled!(Level::TRACE) {
+                    trace!("service {domain}: name={canonical_name:?} type={canonical_type:?} domain={canonical_domain:?} flags={flags:#x}");
+                    for (priority, weight, port, hostname, ips, domain) in &srvs {
+                        trace!("srv: priority={priority} weight={weight} port={port} hostname={hostname:?} domain={domain:?}");
+                        for (ifindex, af, address) in ips {
+                            trace!(
+                                "address: ifindex={ifindex} af={af} address={}",
+                                hex(address)
+                            );
+                        }
+                    }
+                    for record in &records {
+                        trace!("txt: {}", hex(record));
+                    }
+                }
+
+                // Name is already unescaped, type and domain are in presentation format,
+                // while the PTR target is raw
+                let canonical = format!(
+                    "{canonical_name}.{}.{}",
+                    unescape_name(&canonical_type),
+                    unescape_name(&canonical_domain)
+                );
+                if canonical != domain {
+                    debug!("{domain} resolved to {canonical}");
+                }
+                if !seen.insert(canonical.clone()) {
+                    debug!("{canonical} was already resolved, skipping alias {domain}");
+                    continue;
+                }
+                let mut instance =
+                    device_name(&canonical_name).or_else(|| instance_name(&domain, &service));
+
+                let mut txt = Txt::new(
+                    records
+                        .into_iter()
+                        // NUL can't be passed in properties
+                        .map(|r| String::from_utf8_lossy(&r).replace('\0', ""))
+                        .collect(),
+                );
+                if let Some(companion) = device_id(&canonical_name).and_then(|id| airplay.get(&id))
+                {
+                    debug!(
+                        "{canonical} is correlated with AirPlay service {:?}",
+                        companion.name
+                    );
+                    txt = companion.merge(&txt);
+                    if companion.name.is_some() {
+                        instance = companion.name.clone();
+                    }
+                }
+
+                // Device may advertise multiple targets, only the primary one is used.
+                // Weighted choice is seeded by the name, so it stays the same between scans
+                let mut hasher = DefaultHasher::new();
+                domain.hash(&mut hasher);
+                let Some(srv) = select_srv(srvs, |srv| (srv.0, srv.1), hasher.finish()) else {
+                    warn!("no srv records for {domain}");
+                    continue;
+                };
+                let (_priority, _weight, port, hostname, ips, _domain) = srv;
+                let selected = match srv_sockets(port, ips, zone, family) {
+                    Ok(selected) => selected,
+                    Err(Unusable::PortZero) => {
+                        if skipped.insert(domain.clone()) {
+                            warn!(
+                                "skipping {domain}: advertised port 0, which can't be connected to"
+                            );
+                        }
+                        continue;
+                    }
+                    Err(Unusable::Family(sockets)) => {
+                        if skipped.insert(domain.clone()) {
+                            info!("skipping {domain}: no addresses allowed by --family {family:?}, advertised: {sockets:?}");
+                        }
+                        continue;
+                    }
+                };
+                skipped.remove(&domain);
+                for (ifindex, socket) in selected {
+                    if tx
+                        .send(Event::Discovered(Discovered {
+                            hostname: hostname.clone(),
+                            socket,
+                            ifindex,
+                            domain: Some(domain.clone()),
+                            instance: instance.clone(),
+                            canonical: Some(canonical.clone()),
+                            txt: txt.clone(),
+                        }))
+                        .is_err()
+                    {
+                        error!("receiver is dead");
+                        return;
                     }
                 }
             }
-            std::thread::sleep(Duration::from_secs(3));
+            *last_scan.lock().expect("not poisoned") = Instant::now();
+            trigger.wait(&mut triggered, SCAN_INTERVAL);
         }
     });
     rx
 }
 
+/// Why resolved service instance can't be used
+#[derive(Debug, PartialEq)]
+enum Unusable {
+    PortZero,
+    /// No addresses of allowed family, advertised sockets
+    Family(Vec<(i32, SocketAddr)>),
+}
+
+/// (ifindex, socket) pairs of the SRV target allowed by `family`
+///
+/// Port always comes from the SRV record, address records don't carry one
+fn srv_sockets(
+    port: u16,
+    ips: Vec<(i32, i32, Vec<u8>)>,
+    zone: Option<i32>,
+    family: FamilyPreference,
+) -> result::Result<Vec<(i32, SocketAddr)>, Unusable> {
+    if port == 0 {
+        return Err(Unusable::PortZero);
+    }
+    let sockets: Vec<_> = ips
+        .into_iter()
+        .filter_map(|(ifindex, af, address)| {
+            // Bound interface is used even if resolved reports another one,
+            // as link-local prefix might be the same on multiple interfaces
+            let ifindex = zone.unwrap_or(ifindex);
+            Some((ifindex, socket_addr(ifindex, af, &address, port)?))
+        })
+        .collect();
+    let selected = family.select(&sockets);
+    if selected.is_empty() && !sockets.is_empty() {
+        return Err(Unusable::Family(sockets));
+    }
+    Ok(selected)
+}
+
+/// Pipewire might still be starting up during early boot, so initialization is retried
+/// None if SIGINT/SIGTERM was received while retrying
+fn init_pipewire(attempts: u32) -> Result<Option<(MainLoop, Context<MainLoop>)>> {
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        let result = MainLoop::new().and_then(|pw| {
+            let context = Context::new(&pw)?;
+            // Context creation doesn't require running daemon, check it is reachable
+            context.connect(None)?;
+            Ok((pw, context))
+        });
+        match result {
+            Ok(v) => return Ok(Some(v)),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "pipewire initialization failed (attempt {attempt}/{attempts}): {e}, retrying in {delay:?}"
+                );
+                // Signals don't interrupt the sleep, it is split to notice them in time
+                let deadline = Instant::now() + delay;
+                while Instant::now() < deadline {
+                    if shutdown_requested() {
+                        return Ok(None);
+                    }
+                    std::thread::sleep(SHUTDOWN_POLL.min(deadline - Instant::now()));
+                }
+                delay = (delay * 2).min(Duration::from_secs(10));
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 fn main() -> Result<()> {
-    let pw = pipewire::MainLoop::new()?;
-    let context = Context::new(&pw)?;
+    let opts = Opts::parse();
+    let control_socket = opts.control_socket.clone().unwrap_or_else(default_socket);
+    let command = if opts.status {
+        Some(Command::Status)
+    } else if opts.scan {
+        Some(Command::Scan)
+    } else {
+        None
+    };
+    if let Some(command) = command {
+        match send(&control_socket, command) {
+            Ok(response) => {
+                print!("{response}");
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("no running instance at {}: {e}", control_socket.display());
+                std::process::exit(1);
+            }
+        }
+    }
+    // Opened before forking, so errors are still visible on the terminal
+    let log_target = opts.log_target();
+    let writer = match log_target {
+        LogTarget::File => {
+            let path = opts.log_file.as_ref().expect("required by clap");
+            let file = LogFile::open(path, opts.log_max_size * 1024 * 1024)?;
+            BoxMakeWriter::new(Mutex::new(file))
+        }
+        _ => BoxMakeWriter::new(std::io::stderr),
+    };
+    if opts.daemonize {
+        daemonize()?;
+    }
+    let filter = EnvFilter::builder()
+        .with_default_directive(
+            match opts.verbose {
+                0 => LevelFilter::INFO,
+                1 => LevelFilter::DEBUG,
+                _ => LevelFilter::TRACE,
+            }
+            .into(),
+        )
+        .from_env_lossy();
+    match log_target {
+        #[cfg(feature = "journald")]
+        LogTarget::Journald => tracing_subscriber::registry()
+            .with(filter)
+            .with(JournaldLayer::new()?)
+            .init(),
+        _ => tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_ansi(log_target == LogTarget::Stderr)
+            .with_env_filter(filter)
+            .init(),
+    }
+    let config = Rc::new(RefCell::new(match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    }));
+    watch_signals();
 
-    let mut tunnels = RefCell::new(<HashMap<TunnelKey, Tunnel>>::new());
+    let Some((pw, context)) = init_pipewire(opts.pipewire_retries)? else {
+        info!("shutdown requested while waiting for pipewire");
+        return Ok(());
+    };
+    let context = Rc::new(context);
 
-    let rx = resolved_mdns();
+    if opts.replace_existing {
+        remove_existing(&pw, &context)?;
+    }
+    let existing = ExistingSinks::new(&pw, &context)?;
+    let loader: Rc<dyn ModuleLoader> = if opts.dry_run {
+        Rc::new(DryRunLoader)
+    } else {
+        Rc::new(PipewireLoader {
+            context: context.clone(),
+            module: opts.module.clone(),
+        })
+    };
 
-    let timer = pw.add_timer(move |_t| {
-        let _measurer = Measurer(Instant::now());
-        let Ok(msg) = rx.recv_timeout(Duration::from_millis(0)) else {
-            return;
-        };
-        let key = TunnelKey {
-            hostname: msg.hostname.clone(),
-            socket: msg.socket,
-        };
-        if tunnels.borrow().contains_key(&key) {
-            return;
+    let last_scan = Arc::new(Mutex::new(Instant::now()));
+    let trigger = ScanTrigger::default();
+    let rx = match opts.unicast_server {
+        Some(server) => unicast_discovery(
+            server,
+            &opts.domain,
+            opts.family,
+            Duration::from_millis(opts.dbus_timeout),
+            last_scan.clone(),
+            trigger.clone(),
+        )?,
+        #[cfg(feature = "builtin-mdns")]
+        None if opts.backend == DiscoveryBackend::Builtin => {
+            builtin_discovery(opts.family, last_scan.clone(), trigger.clone())?
         }
-        let readable_name = msg
-            .records
-            .iter()
-            .find_map(|r| r.strip_prefix("am="))
-            .map(|v| v.to_owned())
-            .unwrap_or_else(|| "<unnamed>".to_owned());
-        let address = msg.socket.ip();
-        let port = msg.socket.port();
-        let mut prop = properties! {
-            "raop.ip" => address.to_string(),
-            "raop.ip.version" => match address {
-                IpAddr::V4(_) => "4",
-                IpAddr::V6(_) => "6",
-            },
-            "raop.port" => port.to_string(),
-            "raop.name" => {
-                let mut name = format!("{readable_name}");
-                if address.is_ipv4() {
-                    name.push_str(" (IPv4)");
-                }
-                name
-            },
-            "raop.hostname" => msg.hostname.as_str(),
-        };
-        for record in &msg.records {
-            // comma-separated list contains
-            fn clc(l: &str, v: &str) -> bool {
-                l.split(',').any(|i| i == v)
+        None => resolved_mdns(
+            &opts,
+            config.borrow().resolve.flags(),
+            last_scan.clone(),
+            trigger.clone(),
+        ),
+    };
+
+    // Discovery is considered stuck if there was no successful scan for this long
+    let stall_timeout = SCAN_INTERVAL * opts.stall_scans;
+    let shutdown_timeout = Duration::from_millis(opts.shutdown_timeout);
+    let measure_threshold = Duration::from_micros(opts.measure_threshold);
+    let readd_cooldown = Duration::from_secs(opts.readd_cooldown);
+    let connect_timeout = Duration::from_secs(opts.connect_timeout);
+    let load_rate = Duration::from_millis(opts.load_rate);
+    let daemon = Rc::new(Daemon {
+        opts,
+        backend: Box::new(RaopBackend {
+            loader,
+            config: config.clone(),
+        }),
+        tunnels: RefCell::default(),
+        existing,
+        failures: Failures::default(),
+        cooldown: Cooldown::new(readd_cooldown),
+        connect_backoff: Backoff::new(connect_timeout),
+        config,
+        pending: RefCell::default(),
+    });
+    for device in &daemon.config.borrow().static_devices {
+        daemon.handle(Event::Discovered(device.discovered()));
+    }
+
+    // Events are handled as soon as they are sent, main loop is woken up by the channel
+    let receiver = rx.attach(&pw, {
+        let daemon = daemon.clone();
+        move |event| {
+            let _measurer = Measurer::new(measure_threshold);
+            daemon.handle(event);
+        }
+    });
+    notify("READY=1");
+
+    let status = serve(&control_socket, trigger)
+        .map_err(|e| warn!("failed to open control socket, --status won't work: {e}"))
+        .ok();
+
+    let stalled = Cell::new(false);
+    let timer = pw.add_timer({
+        let pw = pw.clone();
+        let daemon = daemon.clone();
+        move |_t| {
+            if shutdown_requested() {
+                pw.quit();
+                return;
             }
-            if let Some(tp) = record.strip_prefix("tp=") {
-                if tp.split(",").any(|v| v == "UDP") {
-                    prop.insert("raop.transport", "udp")
-                } else if tp.split(",").any(|v| v == "TCP") {
-                    prop.insert("raop.transport", "tcp")
-                } else {
-                    eprintln!("unknown transport: {tp}");
+            let _measurer = Measurer::new(measure_threshold);
+            // Process might be alive, while discovery thread is stuck on something
+            let since_scan = last_scan.lock().expect("not poisoned").elapsed();
+            if since_scan < stall_timeout {
+                if stalled.replace(false) {
+                    info!("discovery is scanning again");
                 }
-            } else if let Some(et) = record.strip_prefix("et=") {
-                if et.split(',').any(|v| v == "1") {
-                    prop.insert("raop.encryption.type", "RSA")
-                } else if et.split(',').any(|v| v == "4") {
-                    prop.insert("raop.encryption.type", "auth_setup")
-                } else {
-                    eprintln!("unknown encryption type: {et}");
-                    prop.insert("raop.encryption.type", "none")
-                }
-            } else if let Some(cn) = record.strip_prefix("cn=") {
-                prop.insert(
-                    "raop.audio.codec",
-                    if clc(cn, "3") {
-                        "AAC-ELD"
-                    } else if clc(cn, "2") {
-                        "AAC"
-                    } else if clc(cn, "1") {
-                        "ALAC"
-                    } else if clc(cn, "0") {
-                        "PCM"
-                    } else {
-                        eprintln!("unknown codec: {cn}");
-                        continue;
-                    },
-                )
+                notify("WATCHDOG=1");
+            } else if !stalled.replace(true) {
+                warn!("no successful scan in {since_scan:?}, discovery is stuck");
+            }
+            if reload_requested() {
+                daemon.reload();
+            }
+            if refresh_requested() {
+                daemon.refresh();
+            }
+            if !connect_timeout.is_zero() {
+                daemon.check_connecting();
+            }
+            if let Some(status) = &status {
+                *status.lock().expect("not poisoned") = daemon.status(since_scan);
             }
         }
-        // prop.insert(key, value);
-        let mut ptr = null_mut();
-        let mut sizeloc = 0;
-
-        let module = unsafe {
-            let stream = open_memstream(&mut ptr, &mut sizeloc);
-            if stream.is_null() {
-                panic!("memstream failed");
-            };
-            fprintf(stream, real_c_string!("{"));
-            pipewire_sys::pw_properties_serialize_dict(stream.cast(), prop.get_dict_ptr(), 0);
-            fprintf(stream, real_c_string!("}"));
-            fclose(stream);
-
-            let module = pipewire_sys::pw_context_load_module(
-                context.as_ptr(),
-                real_c_string!("libpipewire-module-raop-sink"),
-                ptr,
-                null_mut(),
-            );
-            free(ptr.cast());
-
-            module
-        };
-        eprintln!("discovered new tunnel: {key:?}");
-        tunnels.borrow_mut().insert(key, Tunnel { module });
     });
 
     timer.update_timer(Some(Duration::from_millis(1)), Some(Duration::from_secs(3)));
 
+    let load_timer = (load_rate != Duration::ZERO).then(|| {
+        let timer = pw.add_timer({
+            let daemon = daemon.clone();
+            move |_t| daemon.load_next()
+        });
+        timer.update_timer(Some(load_rate), Some(load_rate));
+        timer
+    });
+
     pw.run();
+    info!("shutting down");
+    drop(timer);
+    drop(load_timer);
+    drop(receiver);
+    unload_tunnels(daemon.tunnels.take(), shutdown_timeout);
     Ok(())
 }
 
-struct Measurer(Instant);
+/// Logs time spent in the main loop callback, on drop
+///
+/// Callbacks slower than threshold are logged at debug level, others at trace
+struct Measurer {
+    start: Instant,
+    threshold: Duration,
+}
+impl Measurer {
+    fn new(threshold: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            threshold,
+        }
+    }
+}
 impl Drop for Measurer {
     fn drop(&mut self) {
-        let elapsed = self.0.elapsed();
-        if elapsed < Duration::from_millis(1) {
-            return;
+        let elapsed = self.start.elapsed();
+        if elapsed < self.threshold {
+            trace!("took {elapsed:?}");
+        } else {
+            debug!("took {elapsed:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{resolver::RecordReply, testing::discovered};
+
+    use super::*;
+
+    #[test]
+    fn srv_port_zero() {
+        let ips = || vec![(2, AF_INET4, vec![192, 168, 1, 2])];
+        assert_eq!(
+            srv_sockets(0, ips(), None, FamilyPreference::Ipv4ThenIpv6),
+            Err(Unusable::PortZero)
+        );
+        assert_eq!(
+            srv_sockets(7000, ips(), None, FamilyPreference::Ipv4ThenIpv6),
+            Ok(vec![(2, "192.168.1.2:7000".parse().unwrap())])
+        );
+        // Discovered devices with port 0 are also deferred by add_tunnel
+        let msg = discovered("a.local", "192.168.1.2:0", "A._raop._tcp.local");
+        assert_eq!(msg.missing_field(), Some("port"));
+    }
+
+    #[test]
+    fn srv_family() {
+        let ips = vec![(2, AF_INET4, vec![192, 168, 1, 2])];
+        assert_eq!(
+            srv_sockets(7000, ips, Some(3), FamilyPreference::Ipv6),
+            Err(Unusable::Family(vec![(
+                3,
+                "192.168.1.2:7000".parse().unwrap()
+            )]))
+        );
+    }
+
+    fn host(domain: &str) -> ResolvedHost {
+        ResolvedHost {
+            ifindex: 2,
+            name: format!("{domain}._raop._tcp.local"),
+            domain: domain.to_owned(),
+            retries: REMOVAL_RETRIES,
+        }
+    }
+
+    fn hosts(domains: &[&str]) -> BTreeSet<ResolvedHost> {
+        domains.iter().map(|d| host(d)).collect()
+    }
+
+    fn domains(hosts: &[ResolvedHost]) -> Vec<&str> {
+        hosts.iter().map(|h| h.domain.as_str()).collect()
+    }
+
+    #[test]
+    fn reconcile_addition() {
+        let (known, added, removed) = reconcile(&hosts(&["a"]), hosts(&["a", "b"]), true);
+        assert_eq!(known, hosts(&["a", "b"]));
+        assert_eq!(domains(&added), ["b"]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn reconcile_flap() {
+        let (known, added, removed) = reconcile(&hosts(&["a", "b"]), hosts(&["a"]), true);
+        assert_eq!(known, hosts(&["a", "b"]));
+        assert!(added.is_empty() && removed.is_empty());
+        let b = known.iter().find(|h| h.domain == "b").unwrap();
+        assert_eq!(b.retries, REMOVAL_RETRIES - 1);
+
+        // Reappeared host isn't reported as added
+        let (_, added, removed) = reconcile(&known, hosts(&["a", "b"]), true);
+        assert!(added.is_empty() && removed.is_empty());
+    }
+
+    #[test]
+    fn reconcile_removal() {
+        let mut previous = hosts(&["a", "b"]);
+        previous.replace(ResolvedHost {
+            retries: 0,
+            ..host("b")
+        });
+        let (known, added, removed) = reconcile(&previous, hosts(&["a"]), true);
+        assert_eq!(known, hosts(&["a"]));
+        assert!(added.is_empty());
+        assert_eq!(domains(&removed), ["b"]);
+    }
+
+    fn wire_name(name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        for label in name.split('.') {
+            out.push(label.len() as u8);
+            out.extend(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    /// PTR record, as returned by ResolveRecord
+    fn ptr_record(target: &str) -> RecordReply {
+        let rdata = wire_name(target);
+        ptr_record_raw(rdata.len() as u16, &rdata)
+    }
+
+    fn ptr_record_raw(rd_length: u16, rdata: &[u8]) -> RecordReply {
+        let mut data = wire_name("_raop._tcp.local");
+        data.extend(TYPE_PTR.to_be_bytes());
+        data.extend(CLASS_IN.to_be_bytes());
+        data.extend(120u32.to_be_bytes());
+        data.extend(rd_length.to_be_bytes());
+        data.extend(rdata);
+        (2, CLASS_IN, TYPE_PTR, data)
+    }
+
+    #[test]
+    fn ptr_targets_duplicates() {
+        let targets = ptr_targets(
+            vec![
+                ptr_record("Living._raop._tcp.local"),
+                ptr_record("Kitchen._raop._tcp.local"),
+                ptr_record("Living._raop._tcp.local"),
+                ptr_record("LIVING._raop._TCP.local"),
+            ],
+            "_raop._tcp.local",
+        );
+        assert_eq!(
+            targets.into_iter().collect::<Vec<_>>(),
+            ["Kitchen._raop._tcp.local", "Living._raop._tcp.local"]
+        );
+    }
+
+    #[test]
+    fn ptr_targets_malformed_rdata() {
+        let name = wire_name("Living._raop._tcp.local");
+        let mut trailing = name.clone();
+        trailing.push(0);
+        let targets = ptr_targets(
+            vec![
+                ptr_record_raw(trailing.len() as u16, &trailing),
+                // rd_length cuts the name short
+                ptr_record_raw(name.len() as u16 - 3, &name),
+                // rd_length is past the end of the record
+                ptr_record_raw(name.len() as u16 + 1, &name),
+                ptr_record("Kitchen._raop._tcp.local"),
+            ],
+            "_raop._tcp.local",
+        );
+        assert_eq!(
+            targets.into_iter().collect::<Vec<_>>(),
+            ["Kitchen._raop._tcp.local"]
+        );
+    }
+
+    #[test]
+    fn ptr_targets_other_service() {
+        let targets = ptr_targets(
+            vec![ptr_record("Living._airplay._tcp.local")],
+            "_raop._tcp.local",
+        );
+        assert!(targets.is_empty());
+    }
+
+    #[test]
+    fn reconcile_empty_scan() {
+        let (known, added, removed) = reconcile(&hosts(&["a", "b"]), BTreeSet::new(), true);
+        assert_eq!(known, hosts(&["a", "b"]));
+        assert!(added.is_empty() && removed.is_empty());
+    }
+
+    #[test]
+    fn reconcile_removal_after_retries() {
+        let mut known = hosts(&["a", "b"]);
+        for scan in 1..=REMOVAL_RETRIES + 1 {
+            let (next, _, removed) = reconcile(&known, hosts(&["a"]), true);
+            if scan <= REMOVAL_RETRIES {
+                assert!(removed.is_empty(), "removed after {scan} scans");
+            } else {
+                assert_eq!(domains(&removed), ["b"]);
+            }
+            known = next;
+        }
+        assert_eq!(known, hosts(&["a"]));
+    }
+
+    #[test]
+    fn reconcile_reappearance_resets_retries() {
+        let mut known = hosts(&["a"]);
+        for _ in 0..REMOVAL_RETRIES {
+            known = reconcile(&known, BTreeSet::new(), true).0;
+        }
+        assert_eq!(known.first().unwrap().retries, 0);
+        known = reconcile(&known, hosts(&["a"]), true).0;
+        assert_eq!(known.first().unwrap().retries, REMOVAL_RETRIES);
+        // Full grace period again
+        for _ in 0..REMOVAL_RETRIES {
+            let (next, _, removed) = reconcile(&known, BTreeSet::new(), true);
+            assert!(removed.is_empty());
+            known = next;
+        }
+    }
+
+    #[test]
+    fn reconcile_before_baseline() {
+        let mut known = hosts(&["a"]);
+        for _ in 0..REMOVAL_RETRIES + 2 {
+            let (next, added, removed) = reconcile(&known, BTreeSet::new(), false);
+            assert!(added.is_empty() && removed.is_empty());
+            known = next;
+        }
+        assert_eq!(known.first().unwrap().retries, REMOVAL_RETRIES);
+    }
+
+    #[test]
+    fn known_hosts_empty_scan_is_not_baseline() {
+        let mut known = KnownHosts::default();
+        known.update(BTreeSet::new());
+        assert!(!known.baseline);
+        known.update(hosts(&["a", "b"]));
+        assert!(!known.baseline);
+        // Cache flush right after startup
+        known.update(BTreeSet::new());
+        assert!(!known.baseline);
+        assert_eq!(known.hosts.first().unwrap().retries, REMOVAL_RETRIES);
+        known.update(hosts(&["a"]));
+        assert!(known.baseline);
+        assert_eq!(known.hosts, hosts(&["a", "b"]));
+    }
+
+    #[test]
+    fn known_hosts_baseline_after_max_scans() {
+        let mut known = KnownHosts::default();
+        for scan in 1..=BASELINE_MAX_SCANS {
+            known.update(BTreeSet::new());
+            assert_eq!(known.baseline, scan == BASELINE_MAX_SCANS);
+        }
+    }
+
+    #[test]
+    fn known_hosts_removal() {
+        let mut known = KnownHosts::default();
+        let (added, _) = known.update(hosts(&["a", "b"]));
+        assert_eq!(domains(&added), ["a", "b"]);
+        // Nothing new, removals are tracked from now on
+        known.update(hosts(&["a", "b"]));
+        assert!(known.baseline);
+        for _ in 0..REMOVAL_RETRIES {
+            let (added, removed) = known.update(hosts(&["a"]));
+            assert!(added.is_empty() && removed.is_empty());
         }
-        eprintln!("took {elapsed:?}")
+        let (_, removed) = known.update(hosts(&["a"]));
+        assert_eq!(domains(&removed), ["b"]);
+        assert_eq!(known.hosts, hosts(&["a"]));
     }
 }
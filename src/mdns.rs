@@ -0,0 +1,130 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use pipewire::channel::Receiver;
+use tracing::{debug, info};
+
+use crate::{
+    addr::FamilyPreference,
+    dns::{encode_query, parse_message, Record},
+    trigger::ScanTrigger,
+    unicast::{discovery, Querier},
+    Error, Event, Result, CLASS_IN, SERVICE, TYPE_PTR,
+};
+
+/// mDNS group and port, RFC 6762 3
+const MDNS_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// Responses are collected for this long, as every responder replies separately,
+/// after random delay of up to 120ms for shared records
+const RESPONSE_WINDOW: Duration = Duration::from_secs(1);
+
+/// One-shot mDNS querier, RFC 6762 5.1
+///
+/// Queries are sent from an ephemeral port, so responders reply directly to it,
+/// and nothing has to be joined or bound to 5353, which is usually taken by resolved
+/// or avahi. Queries are only sent on the interface of the default multicast route
+struct MdnsClient {
+    socket: UdpSocket,
+    id: u16,
+    /// Records of the last PTR query, responders include SRV, TXT and addresses of
+    /// the instances as additional records, so they don't have to be queried again
+    additional: Vec<Record>,
+}
+
+impl MdnsClient {
+    fn new() -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        // Responders ignore packets which might come from other networks
+        socket.set_multicast_ttl_v4(255)?;
+        Ok(Self {
+            socket,
+            additional: Vec::new(),
+            // Only used to match responses, doesn't need to be unpredictable
+            id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos() as u16),
+        })
+    }
+}
+
+impl Querier for MdnsClient {
+    fn query(&mut self, name: &str, type_: u16) -> Result<Vec<Record>> {
+        if type_ != TYPE_PTR
+            && self
+                .additional
+                .iter()
+                .any(|r| r.type_ == type_ && r.name.eq_ignore_ascii_case(name))
+        {
+            return Ok(self.additional.clone());
+        }
+        let records = self.collect(name, type_)?;
+        if type_ == TYPE_PTR {
+            self.additional = records.clone();
+        }
+        Ok(records)
+    }
+}
+
+impl MdnsClient {
+    /// Records of all responses received within the window
+    fn collect(&mut self, name: &str, type_: u16) -> Result<Vec<Record>> {
+        self.id = self.id.wrapping_add(1);
+        let query = encode_query(self.id, 0, name, type_)
+            .ok_or_else(|| Error::Dns(format!("{name} can't be encoded")))?;
+        self.socket.send_to(&query, MDNS_GROUP)?;
+        let deadline = Instant::now() + RESPONSE_WINDOW;
+        let mut records = Vec::new();
+        let mut buf = [0; 9000];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(records);
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(v) => v,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(records)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let data = &buf[..len];
+            let message = match parse_message(data) {
+                Ok((_rest, message)) => message,
+                Err(e) => {
+                    // Other responders might still answer correctly
+                    debug!("{}", Error::nom("mdns message", data, e));
+                    continue;
+                }
+            };
+            if !message.is_response() || message.id != self.id {
+                debug!("unexpected message from {from}, id {}", message.id);
+                continue;
+            }
+            debug!("{} records from {from}", message.records.len());
+            records.extend(message.records.into_iter().filter(|r| r.class == CLASS_IN));
+        }
+    }
+}
+
+/// Discover devices by sending mDNS queries directly, for systems where mDNS of resolved is unreliable
+pub(crate) fn builtin_discovery(
+    family: FamilyPreference,
+    last_scan: Arc<Mutex<Instant>>,
+    trigger: ScanTrigger,
+) -> Result<Receiver<Event>> {
+    let service = format!("{SERVICE}.local");
+    let client = MdnsClient::new()?;
+    info!("discovering {service} using builtin mDNS querier");
+    Ok(discovery(client, service, family, last_scan, trigger))
+}
@@ -0,0 +1,30 @@
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+};
+
+use tracing::warn;
+
+/// Send sd_notify(3) message to the service manager, does nothing if not running under systemd
+pub(crate) fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = (|| {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    })();
+    if let Err(e) = result {
+        warn!("failed to notify service manager: {e}");
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    ffi::{CString, NulError},
+    net::{AddrParseError, IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use clap::{ArgAction, Parser, ValueEnum};
+use dbus::{strings::BusName, Path};
+
+use crate::{
+    addr::{FamilyPreference, Interface},
+    airplay::Codec,
+    resolver::ResolveFlags,
+    template::NameTemplate,
+    DEST, PATH,
+};
+
+#[derive(Parser, Debug)]
+#[command(about = "Discover RAOP devices using systemd-resolved, and attach them to pipewire")]
+pub(crate) struct Opts {
+    /// Increase logging verbosity, `-vv` dumps raw resolve1 responses.
+    /// RUST_LOG takes precedence, if set
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Print tunnels of the running instance and exit
+    #[arg(long)]
+    pub status: bool,
+    /// Make the running instance scan now, instead of waiting for the next interval, and exit
+    #[arg(long, conflicts_with = "status")]
+    pub scan: bool,
+    /// Unix socket for --status and --scan, `$XDG_RUNTIME_DIR/pw-resolved-discover.sock` by default
+    #[arg(long)]
+    pub control_socket: Option<PathBuf>,
+    /// Where logs are written, `file` by default if --log-file is set, `stderr` otherwise
+    #[arg(long, value_enum)]
+    pub log_target: Option<LogTarget>,
+    /// Write logs to this file (appending) instead of stderr
+    #[arg(long, required_if_eq("log_target", "file"))]
+    pub log_file: Option<PathBuf>,
+    /// Rotate log file when it grows over this many MiB, previous file is kept
+    /// with `.1` suffix. 0 to disable
+    #[arg(long, default_value_t = 0, requires = "log_file")]
+    pub log_max_size: u64,
+    /// Fork into the background, for running without service manager.
+    /// Requires --log-file, as stderr is detached
+    #[arg(long, requires = "log_file")]
+    pub daemonize: bool,
+    /// Address families to use for tunnels
+    // FIXME: Ipv6 doesn't work, RAOP sink doesn't supports link-local addresses
+    // TODO: Should be raop.ip.scope_id be added to pipewire module?
+    #[arg(long, value_enum, default_value_t = FamilyPreference::Ipv4)]
+    pub family: FamilyPreference,
+    /// DNS-SD browse domain, domains other than `local` are queried using unicast DNS
+    #[arg(long, default_value = "local")]
+    pub domain: String,
+    /// Warn if no devices were found after this many consecutive scans
+    #[arg(long, default_value_t = 5)]
+    pub empty_scans_warning: u32,
+    /// Warn, and stop pinging systemd watchdog, if no scan succeeded in this many scan intervals (3s)
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    pub stall_scans: u32,
+    /// Resolve at most this many service instances per scan, rotating between them on
+    /// crowded networks. 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    pub max_resolves: usize,
+    /// Device reappearing within this many seconds after removal only gets its sink back
+    /// after staying present for that long, dampens churn of flapping devices. 0 to disable
+    #[arg(long, default_value_t = 0)]
+    pub readd_cooldown: u64,
+    /// Unload sink if its node doesn't appear in the graph within this many seconds after
+    /// loading the module, and don't retry the device for the same time. 0 to disable
+    #[arg(long, default_value_t = 0)]
+    pub connect_timeout: u64,
+    /// Remove sinks left by previous instances before discovery begins
+    #[arg(long)]
+    pub replace_existing: bool,
+    /// Path to TOML configuration file
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Bypass resolved cache, always query the network, useful if cached address of a device is stale
+    #[arg(long, conflicts_with = "no_network")]
+    pub no_cache: bool,
+    /// Only use resolved cache, don't generate network traffic
+    #[arg(long)]
+    pub no_network: bool,
+    /// Only discover devices on this interface, and always use it as the zone of
+    /// link-local IPv6 addresses, regardless of the interface reported by resolved
+    #[arg(long, value_parser = Interface::parse, conflicts_with = "unicast_server")]
+    pub interface: Option<Interface>,
+    /// When query on all interfaces finds nothing, query every link known to resolved
+    /// separately, for configurations where per-link mDNS settings differ
+    #[arg(long, conflicts_with_all = ["interface", "unicast_server"])]
+    pub link_fallback: bool,
+    /// Query this DNS-SD server directly (`ip` or `ip:port`) instead of resolved,
+    /// for devices in other subnets, use with --domain
+    #[arg(long, value_parser = parse_server, conflicts_with = "airplay")]
+    pub unicast_server: Option<SocketAddr>,
+    /// How devices are discovered
+    #[arg(long, value_enum, default_value_t = DiscoveryBackend::Resolved)]
+    pub backend: DiscoveryBackend,
+    /// Address of the system bus, i.e `unix:path=/run/host/dbus/system_bus_socket`,
+    /// when not set, the default system bus socket is used
+    #[arg(long, env = "DBUS_SYSTEM_BUS_ADDRESS")]
+    pub dbus_address: Option<String>,
+    /// Bus name of resolve1 service, may be changed to point at a mock
+    #[arg(long, env = "PW_RESOLVED_DISCOVER_RESOLVE1_DEST", default_value = DEST, value_parser = parse_bus_name)]
+    pub resolve1_dest: BusName<'static>,
+    /// Object path of resolve1 manager
+    #[arg(long, env = "PW_RESOLVED_DISCOVER_RESOLVE1_PATH", default_value = PATH, value_parser = parse_path)]
+    pub resolve1_path: Path<'static>,
+    /// Timeout for resolve1 D-Bus calls (and --unicast-server queries), in milliseconds
+    #[arg(
+        long,
+        env = "PW_RESOLVED_DISCOVER_DBUS_TIMEOUT",
+        default_value_t = 2000
+    )]
+    pub dbus_timeout: u64,
+    /// Main loop callbacks slower than this are logged at debug level (others at trace), in microseconds
+    #[arg(long, default_value_t = 1000)]
+    pub measure_threshold: u64,
+    /// How long to wait for modules to unload on exit, in milliseconds
+    #[arg(long, default_value_t = 3000)]
+    pub shutdown_timeout: u64,
+    /// Load at most one module every this many milliseconds, smoothing the burst of loads
+    /// when many devices are discovered at once. 0 to load immediately
+    #[arg(long, default_value_t = 0)]
+    pub load_rate: u64,
+    /// Only log arguments of modules which would be loaded
+    #[arg(long)]
+    pub dry_run: bool,
+    /// How many times to try connecting to pipewire on startup
+    #[arg(long, default_value_t = 10)]
+    pub pipewire_retries: u32,
+    /// Don't guess codec/transport/encryption, only pass address and raw TXT records to the module,
+    /// useful to check if a problem is caused by property mapping
+    #[arg(long)]
+    pub raw: bool,
+    /// Pipewire module to load for every discovered device
+    #[arg(long, default_value = "libpipewire-module-raop-sink", value_parser = parse_cstring)]
+    pub module: CString,
+    /// Namespace of module properties, should match --module
+    #[arg(long, default_value = "raop")]
+    pub namespace: String,
+    /// Keep sink of the device with this name (or model) even after it disappears,
+    /// useful for devices which drop off mDNS while sleeping.
+    /// Case-insensitive match of the whole name, `*` matches any sequence of characters
+    #[arg(long)]
+    pub pin: Vec<String>,
+    /// Force codec instead of the one picked from `cn=`, either for all devices (`ALAC`),
+    /// or for devices matching the name (`ALAC:kitchen`), may be repeated
+    #[arg(long, value_parser = CodecOverride::parse)]
+    pub codec: Vec<CodecOverride>,
+    /// Latency of sinks using the codec, in milliseconds, i.e `ALAC=2000`, may be repeated.
+    /// Takes precedence over `[latency]` of the config
+    #[arg(long, value_parser = CodecLatency::parse)]
+    pub codec_latency: Vec<CodecLatency>,
+    /// Use uncompressed PCM for devices advertising digital audio support (`da=true`)
+    /// and PCM in `cn=`, instead of the most preferred advertised codec
+    #[arg(long)]
+    pub prefer_pcm: bool,
+    /// Name of devices which don't advertise it, hostname is appended to keep names distinct
+    #[arg(long, default_value = "Unnamed AirPlay device")]
+    pub unnamed: String,
+    /// Sink name template, placeholders are `{name}`, `{model}`, `{host}` and `{family}`,
+    /// i.e `{name} (AirPlay)`. By default device name is used, with ` (IPv4)` suffix for IPv4 addresses
+    #[arg(long, value_parser = NameTemplate::parse)]
+    pub name_template: Option<NameTemplate>,
+    /// Also discover `_airplay._tcp` services, and use their name and capabilities
+    /// for RAOP devices with the same device id
+    #[arg(long)]
+    pub airplay: bool,
+    /// Encryption used for devices advertising both RSA and auth_setup (`et=1,4`),
+    /// `auto` picks auth_setup for AirPlay 2 firmwares (`vs=` 300 and above), and RSA otherwise
+    #[arg(long, value_enum, default_value_t = EncryptionPreference::Auto)]
+    pub prefer_encryption: EncryptionPreference,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum EncryptionPreference {
+    Auto,
+    Rsa,
+    AuthSetup,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DiscoveryBackend {
+    /// resolve1 of systemd-resolved
+    Resolved,
+    /// Own mDNS queries, only on the interface of the default multicast route
+    #[cfg(feature = "builtin-mdns")]
+    Builtin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogTarget {
+    Stderr,
+    File,
+    /// Native journal protocol, with event fields as structured journal fields
+    #[cfg(feature = "journald")]
+    Journald,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CodecOverride {
+    pub codec: Codec,
+    /// Device name pattern, all devices if None
+    pub device: Option<String>,
+}
+
+impl CodecOverride {
+    fn parse(value: &str) -> Result<Self, String> {
+        let (codec, device) = match value.split_once(':') {
+            Some((codec, device)) => (codec, Some(device.to_owned())),
+            None => (value, None),
+        };
+        let codec = Codec::from_name(codec).ok_or_else(|| {
+            format!("unknown codec {codec:?}, expected PCM, ALAC, AAC or AAC-ELD")
+        })?;
+        Ok(Self { codec, device })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CodecLatency {
+    pub codec: Codec,
+    pub latency_ms: u32,
+}
+
+impl CodecLatency {
+    fn parse(value: &str) -> Result<Self, String> {
+        let Some((codec, latency)) = value.split_once('=') else {
+            return Err(format!("expected CODEC=MS, got {value:?}"));
+        };
+        let codec = Codec::from_name(codec).ok_or_else(|| {
+            format!("unknown codec {codec:?}, expected PCM, ALAC, AAC or AAC-ELD")
+        })?;
+        let latency_ms = latency
+            .parse()
+            .map_err(|e| format!("invalid latency {latency:?}: {e}"))?;
+        Ok(Self { codec, latency_ms })
+    }
+}
+
+fn parse_bus_name(value: &str) -> Result<BusName<'static>, String> {
+    BusName::new(value.to_owned())
+}
+
+fn parse_path(value: &str) -> Result<Path<'static>, String> {
+    Path::new(value.to_owned())
+}
+
+fn parse_cstring(value: &str) -> Result<CString, NulError> {
+    CString::new(value)
+}
+
+impl Opts {
+    pub(crate) fn log_target(&self) -> LogTarget {
+        self.log_target.unwrap_or(if self.log_file.is_some() {
+            LogTarget::File
+        } else {
+            LogTarget::Stderr
+        })
+    }
+
+    /// Cache flags of resolve1 calls
+    pub(crate) fn cache_flags(&self) -> ResolveFlags {
+        let mut flags = ResolveFlags::default();
+        if self.no_cache {
+            flags = flags | ResolveFlags::NO_CACHE | ResolveFlags::NO_SYNTHESIZE;
+        }
+        if self.no_network {
+            flags = flags | ResolveFlags::NO_NETWORK;
+        }
+        flags
+    }
+}
+
+/// Port defaults to 53
+fn parse_server(value: &str) -> Result<SocketAddr, AddrParseError> {
+    value
+        .parse()
+        .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+}
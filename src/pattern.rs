@@ -0,0 +1,51 @@
+/// Match device name (or model) against user-provided pattern
+///
+/// Both are compared case-insensitively, ignoring surrounding whitespace.
+/// Pattern must match the whole name, `*` in it matches any sequence of characters
+pub(crate) fn name_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let name = name.trim().to_lowercase();
+    if !pattern.contains('*') {
+        return name == pattern;
+    }
+    let mut parts = pattern.split('*');
+    let first = parts.next().expect("split yields at least one part");
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let last = parts.next_back().expect("pattern contains *");
+    for part in parts {
+        let Some(pos) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[pos + part.len()..];
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        assert!(name_matches(" LIVING room ", "Living Room"));
+        // Plain name doesn't match part of the name
+        assert!(!name_matches("living", "Living Room"));
+        assert!(!name_matches("room", "Living Room"));
+        assert!(!name_matches("kitchen", "Living Room"));
+    }
+
+    #[test]
+    fn glob() {
+        assert!(name_matches("*", "Living Room"));
+        assert!(name_matches("living*", "Living Room"));
+        assert!(name_matches("*room", "Living Room"));
+        assert!(name_matches("l*g*m", "Living Room"));
+        // Glob must match the whole name
+        assert!(!name_matches("iving*", "Living Room"));
+        assert!(!name_matches("*livin", "Living Room"));
+        // Prefix and suffix can't overlap
+        assert!(!name_matches("ab*ba", "aba"));
+    }
+}
@@ -0,0 +1,305 @@
+use std::net::IpAddr;
+
+use pipewire::{
+    properties,
+    spa::{ReadableDict, WritableDict},
+    Properties,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    airplay::{AudioFlags, Codec, Encryption, Metadata, Quirk, ServerVersion},
+    config::LatencyConfig,
+    opts::{EncryptionPreference, Opts},
+    pattern::name_matches,
+    txt::list_contains,
+    Discovered, TunnelKey,
+};
+
+/// Map discovered TXT records to the module arguments
+pub(crate) fn raop_properties(
+    msg: &Discovered,
+    opts: &Opts,
+    latency: &LatencyConfig,
+    name: &str,
+) -> Properties {
+    let key = msg.key();
+    let model = msg.model();
+    let address = msg.socket.ip();
+    let port = msg.socket.port();
+    // Properties are namespaced for the loaded module, `raop.ip` by default
+    let ns = |key: &str| format!("{}.{key}", opts.namespace);
+    let mut prop = properties! {
+        ns("ip") => address.to_string(),
+        ns("port") => port.to_string(),
+        ns("hostname") => msg.hostname.as_str(),
+    };
+    if opts.raw {
+        // Only pass what device advertised, to compare against module defaults
+        for record in msg.txt.records() {
+            if let Some((key, value)) = record.split_once('=') {
+                prop.insert(ns(&format!("txt.{key}")), value);
+            }
+        }
+    } else {
+        let readable_name = msg.readable_name(&opts.unnamed);
+        prop.insert(ns("name"), name);
+        if let Some(tp) = msg.txt("tp") {
+            if list_contains(tp, "UDP") {
+                prop.insert(ns("transport"), "udp")
+            } else if list_contains(tp, "TCP") {
+                prop.insert(ns("transport"), "tcp")
+            } else {
+                warn!("unknown transport: {tp}");
+            }
+        }
+        let version = msg.txt("vs").and_then(|vs| ServerVersion::parse(vs).ok());
+        if let Some(et) = msg.txt("et") {
+            let prefer_auth_setup = match opts.prefer_encryption {
+                EncryptionPreference::Auto => version
+                    .as_ref()
+                    .is_some_and(ServerVersion::prefers_auth_setup),
+                EncryptionPreference::Rsa => false,
+                EncryptionPreference::AuthSetup => true,
+            };
+            let encryption = Encryption::from_et(et, prefer_auth_setup);
+            if Encryption::is_ambiguous(et) {
+                info!(
+                    "{key:?} advertises both RSA and auth_setup, using {encryption:?} (--prefer-encryption {:?}, vs={})",
+                    opts.prefer_encryption,
+                    msg.txt("vs").unwrap_or("unknown"),
+                );
+            }
+            match encryption.and_then(Encryption::name) {
+                Some(name) => prop.insert(ns("encryption.type"), name),
+                None => {
+                    warn!("unknown encryption type: {et}");
+                    prop.insert(ns("encryption.type"), "none")
+                }
+            }
+        }
+        if let Some(cn) = msg.txt("cn") {
+            let pcm = opts.prefer_pcm
+                && AudioFlags::from_txt(&msg.txt).digital_audio == Some(true)
+                && list_contains(cn, &Codec::Pcm.cn().to_string());
+            match Codec::from_cn(cn) {
+                Some(_) if pcm => {
+                    debug!("{key:?} supports digital audio, using PCM");
+                    prop.insert(ns("audio.codec"), Codec::Pcm.name())
+                }
+                Some(codec) => prop.insert(ns("audio.codec"), codec.name()),
+                None => warn!("unknown codec: {cn}"),
+            }
+        }
+        // Device-specific override takes precedence over global one
+        let forced = opts
+            .codec
+            .iter()
+            .filter(|o| {
+                o.device.as_ref().is_some_and(|p| {
+                    name_matches(p, &readable_name) || model.is_some_and(|m| name_matches(p, m))
+                })
+            })
+            .chain(opts.codec.iter().filter(|o| o.device.is_none()))
+            .next();
+        if let Some(forced) = forced {
+            let codec = forced.codec;
+            if !msg
+                .txt("cn")
+                .is_some_and(|cn| list_contains(cn, &codec.cn().to_string()))
+            {
+                warn!(
+                    "forcing codec {} for {key:?}, but it isn't advertised by the device",
+                    codec.name()
+                );
+            }
+            prop.insert(ns("audio.codec"), codec.name());
+        }
+        // Always set keys required by the module, for devices with minimal TXT records
+        for (name, default) in [("transport", "udp"), ("encryption.type", "none")] {
+            if prop.get(&ns(name)).is_none() {
+                debug!("{name} is not advertised for {key:?}, using {default}");
+                prop.insert(ns(name), default);
+            }
+        }
+        // Later flags take precedence, config is used for codecs without them
+        let latency = prop
+            .get(&ns("audio.codec"))
+            .and_then(Codec::from_name)
+            .map(|codec| {
+                opts.codec_latency
+                    .iter()
+                    .rev()
+                    .find(|l| l.codec == codec)
+                    .map_or_else(|| latency.latency_ms(codec), |l| l.latency_ms)
+            });
+        if let Some(latency) = latency {
+            prop.insert(ns("latency.ms"), latency.to_string());
+        }
+        // Quirks are required for the device to play at all, so they override the above
+        let quirk = model
+            .filter(|_| prop.get(&ns("audio.codec")) == Some("ALAC"))
+            .and_then(|model| Quirk::find(model, version.as_ref()));
+        if let Some(quirk) = quirk {
+            info!("applying quirks for {}: {key:?}", quirk.description);
+            if let Some(encryption) = quirk.encryption {
+                prop.insert(ns("encryption.type"), encryption);
+            }
+            if let Some(latency) = quirk.latency_ms {
+                prop.insert(ns("latency.ms"), latency.to_string());
+            }
+        }
+        // raop-sink has no properties for metadata, supported types are only logged and
+        // listed in the status
+        if let Some(md) = msg.txt("md") {
+            debug!("supported metadata for {key:?}: {}", Metadata::from_md(md));
+        }
+        set_ip_version(&mut prop, &ns("ip"), &ns("ip.version"), &key);
+    }
+    prop
+}
+
+/// Derive ip version from the final address, after all overrides were applied,
+/// so they can't disagree
+fn set_ip_version(prop: &mut Properties, ip: &str, version: &str, key: &TunnelKey) {
+    let Some(address) = prop.get(ip).and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+        warn!("{ip} is not a valid address for {key:?}, not setting {version}");
+        return;
+    };
+    let expected = match address {
+        IpAddr::V4(_) => "4",
+        IpAddr::V6(_) => "6",
+    };
+    match prop.get(version) {
+        Some(v) if v == expected => return,
+        Some(v) => {
+            warn!("{version}={v} doesn't match {ip}={address} for {key:?}, using {expected}")
+        }
+        None => {}
+    }
+    prop.insert(version, expected);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{discovered, opts, with_txt};
+
+    const REQUIRED: [&str; 4] = [
+        "raop.ip",
+        "raop.port",
+        "raop.transport",
+        "raop.encryption.type",
+    ];
+
+    /// Properties of the device at `socket` advertising `txt`
+    fn properties(
+        socket: &str,
+        txt: &[&str],
+        args: &[&str],
+        latency: &LatencyConfig,
+    ) -> Properties {
+        let msg = with_txt(discovered("a.local", socket, "A._raop._tcp.local"), txt);
+        raop_properties(&msg, &opts(args), latency, "Name")
+    }
+
+    #[test]
+    fn required() {
+        let full = [
+            "am=AudioAccessory5,1",
+            "cn=0,1,2,3",
+            "et=0,3,5",
+            "tp=UDP",
+            "vs=366.0",
+        ];
+        let minimal = ["am=AirPort4,107"];
+        for (socket, txt) in [
+            ("[2001:db8::2]:7000", &full[..]),
+            ("192.168.1.3:5000", &minimal[..]),
+        ] {
+            let prop = properties(socket, txt, &[], &LatencyConfig::default());
+            for key in REQUIRED {
+                assert!(prop.get(key).is_some(), "{key} is missing for {socket}");
+            }
+            let socket: std::net::SocketAddr = socket.parse().unwrap();
+            assert_eq!(prop.get("raop.ip"), Some(socket.ip().to_string().as_str()));
+            assert_eq!(
+                prop.get("raop.port"),
+                Some(socket.port().to_string().as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let prop = properties(
+            "192.168.1.3:5000",
+            &["am=AirPort4,107"],
+            &[],
+            &LatencyConfig::default(),
+        );
+        assert_eq!(prop.get("raop.transport"), Some("udp"));
+        assert_eq!(prop.get("raop.encryption.type"), Some("none"));
+        assert_eq!(prop.get("raop.ip.version"), Some("4"));
+        assert_eq!(prop.get("raop.name"), Some("Name"));
+    }
+
+    #[test]
+    fn namespace() {
+        let prop = properties(
+            "192.168.1.3:5000",
+            &["am=AirPort4,107"],
+            &["--namespace", "tunnel"],
+            &LatencyConfig::default(),
+        );
+        for key in REQUIRED {
+            assert!(prop.get(&key.replace("raop.", "tunnel.")).is_some());
+        }
+    }
+
+    #[test]
+    fn prefer_encryption() {
+        let encryption = |args: &[&str], txt: &[&str]| {
+            properties("192.168.1.2:7000", txt, args, &LatencyConfig::default())
+                .get("raop.encryption.type")
+                .map(str::to_owned)
+                .unwrap()
+        };
+        let old = ["cn=1", "et=1,4", "vs=220.68"];
+        let new = ["cn=1", "et=1,4", "vs=366.0"];
+        let unknown = ["cn=1", "et=1,4"];
+        assert_eq!(encryption(&[], &old), "RSA");
+        assert_eq!(encryption(&[], &new), "auth_setup");
+        assert_eq!(encryption(&[], &unknown), "RSA");
+        let args = ["--prefer-encryption", "rsa"];
+        assert_eq!(encryption(&args, &new), "RSA");
+        let args = ["--prefer-encryption", "auth-setup"];
+        assert_eq!(encryption(&args, &old), "auth_setup");
+        assert_eq!(encryption(&args, &unknown), "auth_setup");
+        // Preference only matters when both are advertised
+        assert_eq!(encryption(&args, &["cn=1", "et=0,1"]), "RSA");
+    }
+
+    #[test]
+    fn latency() {
+        let latency = |args: &[&str], config: &LatencyConfig| {
+            // Picks ALAC
+            properties("192.168.1.2:7000", &["cn=0,1", "et=0"], args, config)
+                .get("raop.latency.ms")
+                .map(str::to_owned)
+        };
+        let config = LatencyConfig {
+            alac: 2500,
+            ..Default::default()
+        };
+        assert_eq!(latency(&[], &LatencyConfig::default()).unwrap(), "2000");
+        assert_eq!(latency(&[], &config).unwrap(), "2500");
+        let args = ["--codec-latency", "ALAC=100", "--codec-latency", "alac=200"];
+        assert_eq!(latency(&args, &config).unwrap(), "200");
+        // Only the selected codec is used
+        let args = ["--codec-latency", "AAC=100"];
+        assert_eq!(latency(&args, &config).unwrap(), "2500");
+        let args = ["--codec", "AAC-ELD"];
+        assert_eq!(latency(&args, &LatencyConfig::default()).unwrap(), "500");
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+/// Same warning is logged at most once per this interval
+const INTERVAL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    logged: Instant,
+    suppressed: u32,
+}
+
+static LOGGED: Mutex<BTreeMap<String, Entry>> = Mutex::new(BTreeMap::new());
+
+/// Log warning, unless the same message was logged recently. Suppressed
+/// repeats are still logged at debug level, and counted in the next warning
+pub(crate) fn warn_limited(message: String) {
+    let now = Instant::now();
+    let mut logged = LOGGED.lock().expect("not poisoned");
+    match logged.get_mut(&message) {
+        Some(entry) if now.duration_since(entry.logged) < INTERVAL => {
+            entry.suppressed += 1;
+            debug!("{message}");
+        }
+        Some(entry) => {
+            if entry.suppressed == 0 {
+                warn!("{message}");
+            } else {
+                warn!("{message} (suppressed {} times)", entry.suppressed);
+            }
+            entry.logged = now;
+            entry.suppressed = 0;
+        }
+        None => {
+            warn!("{message}");
+            // Messages which stopped repeating aren't interesting anymore
+            logged.retain(|_, e| now.duration_since(e.logged) < INTERVAL);
+            logged.insert(
+                message,
+                Entry {
+                    logged: now,
+                    suppressed: 0,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suppressed(message: &str) -> Option<u32> {
+        LOGGED.lock().unwrap().get(message).map(|e| e.suppressed)
+    }
+
+    #[test]
+    fn repeats_are_suppressed() {
+        let message = "ratelimit test: repeated";
+        warn_limited(message.to_owned());
+        assert_eq!(suppressed(message), Some(0));
+        warn_limited(message.to_owned());
+        warn_limited(message.to_owned());
+        assert_eq!(suppressed(message), Some(2));
+        // Other message is logged separately
+        warn_limited("ratelimit test: other".to_owned());
+        assert_eq!(suppressed("ratelimit test: other"), Some(0));
+    }
+
+    #[test]
+    fn logged_again_after_interval() {
+        let message = "ratelimit test: expired";
+        warn_limited(message.to_owned());
+        warn_limited(message.to_owned());
+        LOGGED.lock().unwrap().get_mut(message).unwrap().logged -= INTERVAL;
+        warn_limited(message.to_owned());
+        assert_eq!(suppressed(message), Some(0));
+    }
+}
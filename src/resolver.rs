@@ -0,0 +1,374 @@
+use std::{ops::BitOr, sync::Arc, thread, time::Duration};
+
+use dbus::{
+    blocking::{Proxy, SyncConnection},
+    channel::Channel,
+    strings::BusName,
+    Path,
+};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    addr::{interface_display, interfaces},
+    resolve1::OrgFreedesktopResolve1Manager,
+    Result, CLASS_IN, DEST, IFINDEX_ANY, PATH, TYPE_PTR,
+};
+
+/// How many ResolveService calls may be in flight at the same time
+const CONCURRENT_RESOLVES: usize = 8;
+
+/// (ifindex, class, type, data) returned by ResolveRecord
+pub(crate) type RecordReply = (i32, u16, u16, Vec<u8>);
+
+/// (srvs, txt records, name, type, domain, flags) returned by ResolveService
+///
+/// Name, type and domain are the canonical instance name, which differs from the
+/// requested one if it was an alias, name is unescaped (`Living Room`, not `Living\032Room`),
+/// type and domain are in presentation format.
+/// Flags are `SD_RESOLVED_*` bits describing the answer, not the request: protocol
+/// which answered (same bits as [`ResolveFlags::DNS`], [`ResolveFlags::MDNS_V4`]...),
+/// authenticated (`1 << 9`), synthetic (`1 << 19`), and its source: cache (`1 << 20`),
+/// local zone (`1 << 21`), trust anchor (`1 << 22`) or network (`1 << 23`)
+pub(crate) type ServiceReply = (
+    Vec<(u16, u16, u16, String, Vec<(i32, i32, Vec<u8>)>, String)>,
+    Vec<Vec<u8>>,
+    String,
+    String,
+    String,
+    u64,
+);
+
+/// Bus connection, closed when dropped
+///
+/// No match rules or signal handlers are registered, so closing the connection
+/// releases everything it holds on the bus
+struct Connection(SyncConnection);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Queued outgoing messages are discarded on close
+        self.0.channel().flush();
+    }
+}
+
+/// resolve1 client, owning its system bus connection
+///
+/// Clones share the same connection, which may be used from multiple threads,
+/// it is closed once the last clone is dropped
+#[derive(Clone)]
+pub(crate) struct Resolver {
+    connection: Arc<Connection>,
+    timeout: Duration,
+    /// Interface queries are limited to, IFINDEX_ANY for all interfaces
+    ifindex: i32,
+    destination: BusName<'static>,
+    path: Path<'static>,
+    /// Query every link separately when query on all interfaces returns nothing
+    link_fallback: bool,
+}
+
+impl Resolver {
+    /// Connects to the bus at `address` if set, or the default system bus
+    pub(crate) fn new(address: Option<&str>, timeout: Duration, ifindex: i32) -> Result<Self> {
+        let connection = match address {
+            Some(address) => {
+                let mut channel = Channel::open_private(address)?;
+                channel.register()?;
+                SyncConnection::from(channel)
+            }
+            None => SyncConnection::new_system()?,
+        };
+        Ok(Self {
+            connection: Arc::new(Connection(connection)),
+            timeout,
+            ifindex,
+            destination: DEST.into(),
+            path: PATH.into(),
+            link_fallback: false,
+        })
+    }
+
+    /// Use resolve1 service at other name or object path, i.e mock on a private bus
+    pub(crate) fn with_service(self, destination: BusName<'static>, path: Path<'static>) -> Self {
+        Self {
+            destination,
+            path,
+            ..self
+        }
+    }
+
+    /// Some resolved configurations don't return mDNS results of all links for IFINDEX_ANY
+    /// queries, i.e when per-link mDNS settings differ
+    pub(crate) fn with_link_fallback(self, link_fallback: bool) -> Self {
+        Self {
+            link_fallback,
+            ..self
+        }
+    }
+
+    pub(crate) fn proxy(&self) -> Proxy<'_, &SyncConnection> {
+        self.connection
+            .0
+            .with_proxy(self.destination.clone(), self.path.clone(), self.timeout)
+    }
+
+    /// PTR records of the name, queried on every link when nothing was found on all
+    /// interfaces, if link fallback is enabled
+    pub(crate) fn resolve_ptr(
+        &self,
+        name: &str,
+        flags: ResolveFlags,
+    ) -> Result<Vec<RecordReply>, dbus::Error> {
+        let query = |ifindex| {
+            self.proxy()
+                .resolve_record(ifindex, name, CLASS_IN, TYPE_PTR, flags.bits())
+                .map(|(records, _flags)| records)
+        };
+        if !self.link_fallback || self.ifindex != IFINDEX_ANY {
+            return query(self.ifindex);
+        }
+        match query(IFINDEX_ANY) {
+            Ok(records) if !records.is_empty() => return Ok(records),
+            Ok(_) => {}
+            Err(e) if is_negative(&e) => {}
+            Err(e) => return Err(e),
+        }
+        let mut records = Vec::new();
+        for link in self.links() {
+            debug!(
+                "nothing found on all interfaces, querying {name} on {}",
+                interface_display(link)
+            );
+            match query(link) {
+                Ok(found) => records.extend(found),
+                Err(e) if is_negative(&e) => {}
+                Err(e) => debug!("query on {} failed: {e}", interface_display(link)),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Indexes of the links managed by resolved, resolve1 has no call to list them,
+    /// so kernel interfaces unknown to resolved are filtered out
+    fn links(&self) -> Vec<i32> {
+        let interfaces = match interfaces() {
+            Ok(interfaces) => interfaces,
+            Err(e) => {
+                debug!("failed to list interfaces: {e}");
+                return Vec::new();
+            }
+        };
+        interfaces
+            .into_iter()
+            .filter(|i| i.name != "lo")
+            .map(|i| i.index)
+            .filter(|&index| self.proxy().get_link(index).is_ok())
+            .collect()
+    }
+
+    /// Resolve service instances, returning replies in the same order
+    ///
+    /// resolve1 has no batch calls, but ResolveService already returns SRV, TXT and
+    /// addresses at once, and calls over the same connection may run concurrently
+    pub(crate) fn resolve_services(
+        &self,
+        domains: &[String],
+        family: i32,
+        flags: ResolveFlags,
+    ) -> Vec<Result<ServiceReply, dbus::Error>> {
+        let mut replies = Vec::with_capacity(domains.len());
+        for chunk in domains.chunks(CONCURRENT_RESOLVES) {
+            thread::scope(|s| {
+                let handles: Vec<_> = chunk
+                    .iter()
+                    .map(|domain| {
+                        s.spawn(move || {
+                            self.proxy().resolve_service(
+                                self.ifindex,
+                                "",
+                                "",
+                                domain,
+                                family,
+                                flags.bits(),
+                            )
+                        })
+                    })
+                    .collect();
+                replies.extend(
+                    handles
+                        .into_iter()
+                        .map(|h| h.join().expect("resolve thread panicked")),
+                );
+            });
+        }
+        replies
+    }
+}
+
+/// resolved confirmed that the name doesn't exist, as opposed to failing to resolve it
+pub(crate) fn is_negative(error: &dbus::Error) -> bool {
+    matches!(
+        error.name(),
+        Some(
+            "org.freedesktop.resolve1.NoSuchResourceRecord"
+                | "org.freedesktop.resolve1.DnsError.NXDOMAIN"
+        )
+    )
+}
+
+/// `SD_RESOLVED_*` flags of resolve1 calls, see org.freedesktop.resolve1(5)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) struct ResolveFlags(u64);
+
+impl ResolveFlags {
+    /// Unicast DNS, `1 << 0`
+    pub const DNS: Self = Self(1 << 0);
+    /// mDNS over IPv4, `1 << 3`
+    pub const MDNS_V4: Self = Self(1 << 3);
+    /// mDNS over IPv6, `1 << 4`
+    pub const MDNS_V6: Self = Self(1 << 4);
+    /// Don't follow CNAME/DNAME redirections, `1 << 5`
+    pub const NO_CNAME: Self = Self(1 << 5);
+    /// Don't apply search domains, `1 << 8`
+    pub const NO_SEARCH: Self = Self(1 << 8);
+    /// Don't validate DNSSEC, `1 << 10`
+    pub const NO_VALIDATE: Self = Self(1 << 10);
+    /// Don't answer from synthesized local records, `1 << 11`
+    pub const NO_SYNTHESIZE: Self = Self(1 << 11);
+    /// Bypass the cache, always query the network, `1 << 12`
+    pub const NO_CACHE: Self = Self(1 << 12);
+    /// Don't answer from locally registered records, `1 << 13`
+    pub const NO_ZONE: Self = Self(1 << 13);
+    /// Don't answer from DNSSEC trust anchors, `1 << 14`
+    pub const NO_TRUST_ANCHOR: Self = Self(1 << 14);
+    /// Only answer from the cache and local sources, `1 << 15`
+    pub const NO_NETWORK: Self = Self(1 << 15);
+    /// Clamp TTL of cached records to the original one, `1 << 17`
+    pub const CLAMP_TTL: Self = Self(1 << 17);
+
+    pub(crate) fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl BitOr for ResolveFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Named resolve flag, as written in the config
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ResolveFlag {
+    NoCname,
+    NoSearch,
+    NoValidate,
+    NoSynthesize,
+    NoCache,
+    NoZone,
+    NoTrustAnchor,
+    NoNetwork,
+    ClampTtl,
+}
+
+impl ResolveFlag {
+    pub(crate) fn flags(self) -> ResolveFlags {
+        match self {
+            Self::NoCname => ResolveFlags::NO_CNAME,
+            Self::NoSearch => ResolveFlags::NO_SEARCH,
+            Self::NoValidate => ResolveFlags::NO_VALIDATE,
+            Self::NoSynthesize => ResolveFlags::NO_SYNTHESIZE,
+            Self::NoCache => ResolveFlags::NO_CACHE,
+            Self::NoZone => ResolveFlags::NO_ZONE,
+            Self::NoTrustAnchor => ResolveFlags::NO_TRUST_ANCHOR,
+            Self::NoNetwork => ResolveFlags::NO_NETWORK,
+            Self::ClampTtl => ResolveFlags::CLAMP_TTL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        path::PathBuf,
+        process::{Child, Command, Stdio},
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// Private bus, killed on drop
+    struct Bus(Child, PathBuf);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+            let _ = std::fs::remove_dir_all(&self.1);
+        }
+    }
+
+    /// Bus and its address, None if dbus-daemon isn't installed
+    fn private_bus() -> Option<(Bus, String)> {
+        let dir = std::env::temp_dir().join(format!("pw-resolved-discover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .arg(format!("--address=unix:path={}", dir.join("bus").display()))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut bus = Bus(child, dir);
+        let mut address = String::new();
+        BufReader::new(bus.0.stdout.as_mut().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some((bus, address.trim().to_owned()))
+    }
+
+    /// Wait until there are `expected` connections to the bus, except the one checking,
+    /// bus notices disconnects asynchronously
+    fn wait_connections(address: &str, expected: usize) {
+        let start = Instant::now();
+        while connections(address) != expected {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "expected {expected} connections, got {}",
+                connections(address)
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn connections(address: &str) -> usize {
+        let connection = SyncConnection::new_address(address).unwrap();
+        let (names,): (Vec<String>,) = connection
+            .with_proxy("org.freedesktop.DBus", "/", Duration::from_secs(1))
+            .method_call("org.freedesktop.DBus", "ListNames", ())
+            .unwrap();
+        names.iter().filter(|n| n.starts_with(':')).count() - 1
+    }
+
+    #[test]
+    fn connection_is_closed() {
+        let Some((_bus, address)) = private_bus() else {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        };
+        for _ in 0..20 {
+            let resolver =
+                Resolver::new(Some(&address), Duration::from_secs(1), IFINDEX_ANY).unwrap();
+            let clone = resolver.clone();
+            drop(resolver);
+            wait_connections(&address, 1);
+            drop(clone);
+            wait_connections(&address, 0);
+        }
+    }
+}
@@ -1,38 +1,78 @@
 use nom::{
+    bytes::complete::take,
+    error::{Error, ErrorKind},
     number::complete::{be_u16, be_u32, be_u8},
     IResult,
 };
 
+/// Maximum length of name in wire format, RFC 1035 2.3.4
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Top bit of mDNS record class, RFC 6762 10.2
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Class without mDNS cache-flush bit, and whether it was set
+pub(crate) fn split_class(class: u16) -> (u16, bool) {
+    (class & !CACHE_FLUSH, class & CACHE_FLUSH != 0)
+}
+
+/// Root name (single zero byte) is parsed as empty string
 pub(crate) fn parse_name(input: &[u8]) -> IResult<&[u8], String> {
+    if input.is_empty() {
+        // Name should contain at least the root label terminator
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::Eof)));
+    }
     let mut res = String::new();
     let mut i = input;
     loop {
+        if input.len() - i.len() > MAX_NAME_LENGTH {
+            return Err(nom::Err::Error(Error::new(i, ErrorKind::TooLarge)));
+        }
         match be_u8(i)? {
             (remaining, 0) => {
                 // End of the name
                 return Ok((remaining, res));
             }
+            (_, length) if length & 0xc0 != 0 => {
+                // Compression pointer or reserved label type, not supported
+                return Err(nom::Err::Error(Error::new(i, ErrorKind::Verify)));
+            }
             (remaining, length) => {
-                let label_end = length as usize;
-                let label = &remaining[0..label_end];
+                let (remaining, label) = take(length)(remaining)?;
                 let label_str = String::from_utf8_lossy(label);
                 if !res.is_empty() {
                     res.push('.');
                 }
                 res.push_str(&label_str);
-                i = &remaining[label_end..];
+                i = remaining;
             }
         }
     }
 }
 
+/// Typed rdata should take exactly `rd_length` bytes, leftover bytes mean the record is malformed
+pub(crate) fn all_consumed(rest: &[u8]) -> IResult<&[u8], ()> {
+    if !rest.is_empty() {
+        return Err(nom::Err::Error(Error::new(rest, ErrorKind::LengthValue)));
+    }
+    Ok((rest, ()))
+}
+
+/// Name which takes the whole rdata, i.e target of PTR record
+pub(crate) fn parse_rdata_name(rdata: &[u8]) -> IResult<&[u8], String> {
+    let (rest, name) = parse_name(rdata)?;
+    all_consumed(rest)?;
+    Ok((rest, name))
+}
+
 pub(crate) fn parse_rr(input: &[u8]) -> IResult<&[u8], ResourceRecord> {
     let (input, name) = parse_name(input)?;
     let (input, type_) = be_u16(input)?;
     let (input, class) = be_u16(input)?;
+    let (class, cache_flush) = split_class(class);
     let (input, ttl) = be_u32(input)?;
     let (input, rd_length) = be_u16(input)?;
-    let (input, rdata) = nom::bytes::complete::take(rd_length)(input)?;
+    let (input, rdata) = take(rd_length)(input)?;
 
     Ok((
         input,
@@ -40,6 +80,7 @@ pub(crate) fn parse_rr(input: &[u8]) -> IResult<&[u8], ResourceRecord> {
             name,
             type_,
             class,
+            cache_flush,
             ttl,
             rdata: rdata.to_vec(),
         },
@@ -50,8 +91,289 @@ pub(crate) fn parse_rr(input: &[u8]) -> IResult<&[u8], ResourceRecord> {
 pub(crate) struct ResourceRecord {
     pub name: String,
     pub type_: u16,
+    /// Without cache-flush bit
     pub class: u16,
+    /// Record replaces cached records of the same name and type
+    #[allow(unused)]
+    pub cache_flush: bool,
     #[allow(unused)]
     pub ttl: u32,
     pub rdata: Vec<u8>,
 }
+
+/// Strip `.suffix` from the name, names are compared case-insensitively (RFC 4343),
+/// and trailing root dot is ignored
+pub(crate) fn strip_name_suffix<'a>(name: &'a str, suffix: &str) -> Option<&'a str> {
+    let name = name.trim_end_matches('.');
+    let suffix = suffix.trim_end_matches('.');
+    let split = name.len().checked_sub(suffix.len() + 1)?;
+    if !name.is_char_boundary(split) || !name.is_char_boundary(split + 1) {
+        return None;
+    }
+    let (prefix, rest) = name.split_at(split);
+    (rest[1..].eq_ignore_ascii_case(suffix) && rest.starts_with('.')).then_some(prefix)
+}
+
+/// Unescape name in presentation format, i.e `Living\032Room` -> `Living Room`,
+/// only for names returned by resolve1, names from `parse_name` are already raw
+pub(crate) fn unescape_name(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            // \DDD
+            if let Some(digits) = bytes
+                .get(i + 1..i + 4)
+                .filter(|d| d.iter().all(u8::is_ascii_digit))
+            {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |acc, d| acc * 10 + (d - b'0') as u32);
+                if let Ok(value) = u8::try_from(value) {
+                    out.push(value);
+                    i += 4;
+                } else {
+                    // Out of range, kept as is
+                    out.extend_from_slice(&bytes[i..i + 4]);
+                    i += 4;
+                }
+                continue;
+            }
+            // \X
+            if let Some(&c) = bytes.get(i + 1) {
+                out.push(c);
+                i += 2;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Select SRV target according to RFC 2782: lowest priority first,
+/// then weighted random among targets with equal priority
+///
+/// `random` is used to pick weighted target, passing the same value yields the same target
+pub(crate) fn select_srv<T>(
+    srvs: Vec<T>,
+    priority_weight: impl Fn(&T) -> (u16, u16),
+    random: u64,
+) -> Option<T> {
+    let priority = srvs.iter().map(|s| priority_weight(s).0).min()?;
+    let mut candidates: Vec<T> = srvs
+        .into_iter()
+        .filter(|s| priority_weight(s).0 == priority)
+        .collect();
+    let total: u64 = candidates.iter().map(|s| priority_weight(s).1 as u64).sum();
+    let index = if total == 0 {
+        (random % candidates.len() as u64) as usize
+    } else {
+        let target = random % total;
+        let mut running = 0;
+        candidates
+            .iter()
+            .position(|s| {
+                running += priority_weight(s).1 as u64;
+                target < running
+            })
+            .expect("target is less than total weight")
+    };
+    Some(candidates.swap_remove(index))
+}
+
+/// Hex dump for logs
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::error::ErrorKind;
+
+    use super::*;
+
+    fn error_kind<T: std::fmt::Debug>(result: IResult<&[u8], T>) -> ErrorKind {
+        match result {
+            Err(nom::Err::Error(e)) => e.code,
+            other => panic!("expected error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_rdata_name_trailing_bytes() {
+        assert_eq!(
+            parse_rdata_name(b"\x01a\x05local\x00").unwrap().1,
+            "a.local"
+        );
+        assert_eq!(
+            error_kind(parse_rdata_name(b"\x01a\x05local\x00\x00")),
+            ErrorKind::LengthValue
+        );
+        // Name running past rdata
+        assert_eq!(
+            error_kind(parse_rdata_name(b"\x01a\x05loc")),
+            ErrorKind::Eof
+        );
+    }
+
+    #[test]
+    fn parse_name_root() {
+        assert_eq!(
+            parse_name(&[0x00, 0xaa]).unwrap(),
+            (&[0xaa][..], String::new())
+        );
+    }
+
+    #[test]
+    fn parse_name_empty() {
+        assert_eq!(error_kind(parse_name(&[])), ErrorKind::Eof);
+    }
+
+    #[test]
+    fn parse_name_labels() {
+        let (rest, name) = parse_name(b"\x05_raop\x04_tcp\x05local\x00\x00\x0c").unwrap();
+        assert_eq!(name, "_raop._tcp.local");
+        assert_eq!(rest, b"\x00\x0c");
+    }
+
+    #[test]
+    fn parse_name_truncated() {
+        assert_eq!(error_kind(parse_name(b"\x05_raop\x04_t")), ErrorKind::Eof);
+        // Terminator is missing
+        assert_eq!(error_kind(parse_name(b"\x05_raop")), ErrorKind::Eof);
+    }
+
+    #[test]
+    fn parse_name_compressed() {
+        assert_eq!(
+            error_kind(parse_name(b"\x05_raop\xc0\x0c")),
+            ErrorKind::Verify
+        );
+    }
+
+    #[test]
+    fn parse_name_too_long() {
+        let input: Vec<u8> = [b"\x3f".as_slice(), &[b'a'; 63]]
+            .concat()
+            .repeat(5)
+            .into_iter()
+            .chain([0])
+            .collect();
+        assert_eq!(error_kind(parse_name(&input)), ErrorKind::TooLarge);
+    }
+
+    // PTR record for `Living._raop._tcp.local`, as returned by ResolveRecord
+    const PTR: &[u8] = b"\x05_raop\x04_tcp\x05local\x00\x00\x0c\x00\x01\x00\x00\x11\x94\x00\x19\x06Living\x05_raop\x04_tcp\x05local\x00";
+
+    #[test]
+    fn parse_rr_ptr() {
+        let (rest, rr) = parse_rr(PTR).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(rr.name, "_raop._tcp.local");
+        assert_eq!(rr.type_, 12);
+        assert_eq!(rr.class, 1);
+        assert!(!rr.cache_flush);
+        assert_eq!(rr.ttl, 4500);
+        assert_eq!(parse_name(&rr.rdata).unwrap().1, "Living._raop._tcp.local");
+    }
+
+    #[test]
+    fn parse_rr_cache_flush() {
+        let mut record = PTR.to_vec();
+        // Class follows the name and type
+        record[20..22].copy_from_slice(&0x8001u16.to_be_bytes());
+        let (_, rr) = parse_rr(&record).unwrap();
+        assert_eq!(rr.class, 1);
+        assert!(rr.cache_flush);
+    }
+
+    #[test]
+    fn parse_rr_truncated() {
+        // rdata is shorter than rd_length
+        assert_eq!(error_kind(parse_rr(&PTR[..PTR.len() - 1])), ErrorKind::Eof);
+        // Header is incomplete
+        assert_eq!(error_kind(parse_rr(&PTR[..20])), ErrorKind::Eof);
+        assert_eq!(error_kind(parse_rr(&[])), ErrorKind::Eof);
+    }
+
+    #[test]
+    fn split_class_flag() {
+        assert_eq!(split_class(0x0001), (1, false));
+        assert_eq!(split_class(0x8001), (1, true));
+    }
+
+    fn srvs() -> Vec<(&'static str, u16, u16)> {
+        vec![("a", 10, 0), ("b", 0, 10), ("c", 0, 30), ("d", 5, 100)]
+    }
+
+    fn select(srvs: Vec<(&'static str, u16, u16)>, random: u64) -> Option<&'static str> {
+        select_srv(srvs, |(_, p, w)| (*p, *w), random).map(|(t, _, _)| t)
+    }
+
+    #[test]
+    fn select_srv_priority() {
+        // Only the lowest priority is considered, whatever the weights
+        for random in 0..40 {
+            assert!(matches!(select(srvs(), random), Some("b" | "c")));
+        }
+        assert_eq!(select(vec![], 0), None);
+    }
+
+    #[test]
+    fn select_srv_weight() {
+        // b takes 10 of 40, c takes the rest
+        assert_eq!(select(srvs(), 0), Some("b"));
+        assert_eq!(select(srvs(), 9), Some("b"));
+        assert_eq!(select(srvs(), 10), Some("c"));
+        assert_eq!(select(srvs(), 39), Some("c"));
+        assert_eq!(select(srvs(), 40), Some("b"));
+    }
+
+    #[test]
+    fn select_srv_zero_weight() {
+        let srvs = || vec![("a", 0, 0), ("b", 0, 0)];
+        assert_eq!(select(srvs(), 0), Some("a"));
+        assert_eq!(select(srvs(), 1), Some("b"));
+    }
+
+    #[test]
+    fn strip_name_suffix_case() {
+        assert_eq!(
+            strip_name_suffix("Living._RAOP._Tcp.LOCAL.", "_raop._tcp.local"),
+            Some("Living")
+        );
+        assert_eq!(
+            strip_name_suffix("Living._raop._tcp.local", "_RAOP._TCP.Local."),
+            Some("Living")
+        );
+        // Case of the prefix is kept
+        assert_eq!(
+            strip_name_suffix("KiTcHeN._raop._tcp.local", "_raop._tcp.local"),
+            Some("KiTcHeN")
+        );
+        assert_eq!(
+            strip_name_suffix("Living_raop._tcp.local", "_raop._tcp.local"),
+            None
+        );
+        assert_eq!(
+            strip_name_suffix("_raop._tcp.local", "_raop._tcp.local"),
+            None
+        );
+        // Non-ASCII names don't panic
+        assert_eq!(strip_name_suffix("Café.local", "é.local"), None);
+    }
+
+    #[test]
+    fn unescape_name_escapes() {
+        assert_eq!(unescape_name(r"Living\032Room"), "Living Room");
+        assert_eq!(unescape_name(r"A\.B\\C"), r"A.B\C");
+        assert_eq!(unescape_name(r"Caf\195\169"), "Café");
+        // Out of range and incomplete escapes are kept as is
+        assert_eq!(unescape_name(r"a\999"), r"a\999");
+        assert_eq!(unescape_name(r"\256\065"), r"\256A");
+        assert_eq!(unescape_name(r"a\"), r"a\");
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    ffi::{CStr, CString},
+    ptr::null_mut,
+};
+
+use libc::{fclose, fprintf, free, open_memstream};
+use pipewire::{spa::ReadableDict, Properties};
+use real_c_string::real_c_string;
+use tracing::{debug, warn};
+
+use crate::{Error, Result};
+
+/// Serialize properties to the format accepted as module arguments
+///
+/// Serialized string is parsed back and compared with the input, to make sure
+/// values with quotes/backslashes/etc are not mangled
+///
+/// Pure-Rust serialization is used if pipewire serialization is unavailable or fails
+pub(crate) fn serialize_properties(prop: &Properties) -> Result<CString> {
+    if let Some(out) = serialize_dict(prop) {
+        match verify(prop, &out) {
+            Ok(()) => return Ok(out),
+            Err(e) => warn!("{e}, falling back to builtin serialization"),
+        }
+    } else {
+        debug!("memstream is unavailable, using builtin serialization");
+    }
+    let out = serialize_dict_fallback(prop);
+    verify(prop, &out)?;
+    Ok(out)
+}
+
+/// `{ "key": value ... }`, as written by pipewire itself
+///
+/// None if memstream can't be opened
+fn serialize_dict(prop: &Properties) -> Option<CString> {
+    let mut ptr = null_mut();
+    let mut sizeloc = 0;
+    unsafe {
+        let stream = open_memstream(&mut ptr, &mut sizeloc);
+        if stream.is_null() {
+            return None;
+        };
+        fprintf(stream, real_c_string!("{"));
+        pipewire_sys::pw_properties_serialize_dict(stream.cast(), prop.get_dict_ptr(), 0);
+        fprintf(stream, real_c_string!("}"));
+        fclose(stream);
+
+        let out = CStr::from_ptr(ptr).to_owned();
+        free(ptr.cast());
+        Some(out)
+    }
+}
+
+/// `{ "key" = "value" ... }`, values are always written as JSON strings,
+/// which is accepted by pipewire relaxed JSON parser
+fn serialize_dict_fallback(prop: &Properties) -> CString {
+    let mut out = String::from("{");
+    for (key, value) in prop.iter() {
+        out.push(' ');
+        push_json_string(&mut out, key);
+        out.push_str(" = ");
+        push_json_string(&mut out, value);
+    }
+    out.push_str(" }");
+    CString::new(out).expect("properties are read from C strings")
+}
+
+fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Check that `serialized` is parsed by pipewire back to the same properties
+fn verify(prop: &Properties, serialized: &CStr) -> Result<()> {
+    let parsed = unsafe { pipewire_sys::pw_properties_new_string(serialized.as_ptr()) };
+    if parsed.is_null() {
+        return Err(Error::Serialization(format!("unparseable: {serialized:?}")));
+    }
+    let mut result = Ok(());
+    for (key, value) in prop.iter() {
+        let ckey = CString::new(key).expect("key is read from C string");
+        let got = unsafe { pipewire_sys::pw_properties_get(parsed, ckey.as_ptr()) };
+        if got.is_null() || unsafe { CStr::from_ptr(got) }.to_bytes() != value.as_bytes() {
+            result = Err(Error::Serialization(format!("value of {key} is mangled")));
+            break;
+        }
+    }
+    unsafe { pipewire_sys::pw_properties_free(parsed) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use pipewire::properties;
+
+    use super::*;
+
+    /// Fallback output is checked by libpipewire's parser, which reads module arguments
+    fn roundtrip(prop: &Properties) {
+        let out = serialize_dict_fallback(prop);
+        verify(prop, &out).unwrap_or_else(|e| panic!("{e}: {out:?}"));
+    }
+
+    #[test]
+    fn quotes_and_backslashes() {
+        let prop = properties! {
+            "raop.name" => r#"Kitchen "Left" \ Right\"#,
+            "node.description" => r#"\"#,
+        };
+        roundtrip(&prop);
+    }
+
+    fn tricky() -> Properties {
+        properties! {
+            "raop.name" => r#"Kitchen "Left" \ Right\"#,
+            "node.description" => "Café ♫ 客厅",
+            "raop.txt.note" => "tab\tnewline\n{ } = , : [ ]",
+            "raop.empty" => "",
+        }
+    }
+
+    #[test]
+    fn fallback_tricky() {
+        roundtrip(&tricky());
+    }
+
+    /// Same path as module arguments, including the memstream
+    #[test]
+    fn memstream_tricky() {
+        let prop = tricky();
+        let out = serialize_dict(&prop).expect("memstream is available");
+        let out_str = out.to_str().unwrap();
+        assert!(
+            out_str.starts_with('{') && out_str.ends_with('}'),
+            "{out_str}"
+        );
+        verify(&prop, &out).unwrap();
+        assert_eq!(serialize_properties(&prop).unwrap(), out);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            serialize_dict_fallback(&Properties::new()).to_str(),
+            Ok("{ }")
+        );
+    }
+}
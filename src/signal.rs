@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+static REFRESH_REQUESTED: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGHUP => RELOAD_REQUESTED.store(true, Ordering::Relaxed),
+        libc::SIGUSR1 => REFRESH_REQUESTED.store(true, Ordering::Relaxed),
+        _ => SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed),
+    }
+}
+
+/// Request config reload on SIGHUP, sink refresh on SIGUSR1, and shutdown on SIGINT/SIGTERM
+///
+/// Handlers only set flags, which are polled from the main loop
+pub(crate) fn watch_signals() {
+    for signal in [libc::SIGHUP, libc::SIGUSR1, libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            libc::signal(
+                signal,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+    }
+}
+
+/// Whether SIGHUP was received since the last call
+pub(crate) fn reload_requested() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Whether SIGUSR1 was received since the last call
+pub(crate) fn refresh_requested() -> bool {
+    REFRESH_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Whether SIGINT/SIGTERM was received
+pub(crate) fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::Relaxed)
+}
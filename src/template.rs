@@ -0,0 +1,140 @@
+/// Sink name template, i.e `{name} (AirPlay)`
+///
+/// Supported placeholders are `{name}`, `{model}`, `{host}` and `{family}`,
+/// literal braces are written as `{{` and `}}`
+#[derive(Clone, Debug)]
+pub(crate) struct NameTemplate(Vec<Part>);
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Placeholder {
+    Name,
+    Model,
+    Host,
+    Family,
+}
+
+/// Values substituted into the template
+pub(crate) struct NameVars<'a> {
+    pub name: &'a str,
+    pub model: Option<&'a str>,
+    pub host: &'a str,
+    pub family: &'a str,
+}
+
+impl NameTemplate {
+    pub(crate) fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let Some((name, rest)) = chars.as_str().split_once('}') else {
+                        return Err(format!("unclosed placeholder in {template:?}"));
+                    };
+                    let placeholder = match name {
+                        "name" => Placeholder::Name,
+                        "model" => Placeholder::Model,
+                        "host" => Placeholder::Host,
+                        "family" => Placeholder::Family,
+                        _ => {
+                            return Err(format!(
+                                "unknown placeholder {{{name}}}, expected one of {{name}}, {{model}}, {{host}}, {{family}}"
+                            ))
+                        }
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                    chars = rest.chars();
+                }
+                '}' => {
+                    return Err(format!(
+                        "unmatched }} in {template:?}, use }}}} for literal brace"
+                    ))
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self(parts))
+    }
+
+    /// Returns None if expanded name is blank
+    pub(crate) fn expand(&self, vars: &NameVars) -> Option<String> {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Literal(l) => out.push_str(l),
+                Part::Placeholder(Placeholder::Name) => out.push_str(vars.name),
+                Part::Placeholder(Placeholder::Model) => out.push_str(vars.model.unwrap_or("")),
+                Part::Placeholder(Placeholder::Host) => out.push_str(vars.host),
+                Part::Placeholder(Placeholder::Family) => out.push_str(vars.family),
+            }
+        }
+        let out = out.trim();
+        (!out.is_empty()).then(|| out.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARS: NameVars = NameVars {
+        name: "Living Room",
+        model: Some("AudioAccessory5,1"),
+        host: "living.local",
+        family: "IPv6",
+    };
+
+    fn expand(template: &str, vars: &NameVars) -> Option<String> {
+        NameTemplate::parse(template).unwrap().expand(vars)
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            expand("{name} ({model}, {host}, {family})", &VARS).as_deref(),
+            Some("Living Room (AudioAccessory5,1, living.local, IPv6)")
+        );
+        assert_eq!(
+            expand("{{{name}}}", &VARS).as_deref(),
+            Some("{Living Room}")
+        );
+    }
+
+    #[test]
+    fn blank_expansion() {
+        let vars = NameVars {
+            model: None,
+            ..VARS
+        };
+        assert_eq!(expand(" {model} ", &vars), None);
+        assert_eq!(expand("{model} AirPlay", &vars).as_deref(), Some("AirPlay"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(NameTemplate::parse("{name").is_err());
+        assert!(NameTemplate::parse("{serial}").is_err());
+        assert!(NameTemplate::parse("name}").is_err());
+    }
+}
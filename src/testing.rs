@@ -0,0 +1,92 @@
+use std::{
+    cell::{Cell, RefCell},
+    ptr::NonNull,
+    rc::Rc,
+};
+
+use clap::Parser;
+use pipewire::{spa::ReadableDict, Properties};
+use pipewire_sys::pw_impl_module;
+
+use crate::{
+    backend::SinkBackend,
+    loader::{ModuleHandle, ModuleLoader},
+    opts::Opts,
+    txt::Txt,
+    Discovered, Error, Result, KEY_PROP,
+};
+
+pub(crate) fn opts(args: &[&str]) -> Opts {
+    Opts::parse_from([&["pw-resolved-discover"], args].concat())
+}
+
+pub(crate) fn discovered(hostname: &str, socket: &str, domain: &str) -> Discovered {
+    Discovered {
+        hostname: hostname.to_owned(),
+        socket: socket.parse().unwrap(),
+        ifindex: 2,
+        domain: Some(domain.to_owned()),
+        instance: Some(domain.split('.').next().unwrap().to_owned()),
+        canonical: None,
+        txt: Txt::new(vec!["cn=0,1".to_owned(), "et=0".to_owned()]),
+    }
+}
+
+pub(crate) fn with_txt(msg: Discovered, records: &[&str]) -> Discovered {
+    Discovered {
+        txt: Txt::new(records.iter().map(|r| r.to_string()).collect()),
+        ..msg
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LoaderCall {
+    /// Tunnel key the module was tagged with
+    Load(String),
+    Unload(String),
+}
+
+/// Records loads and unloads, without loading anything
+#[derive(Default)]
+pub(crate) struct RecordingLoader {
+    pub calls: RefCell<Vec<LoaderCall>>,
+    /// Following loads fail
+    pub fail: Cell<bool>,
+}
+
+impl ModuleLoader for RecordingLoader {
+    fn load(&self, _args: Properties, tags: Properties) -> Result<NonNull<pw_impl_module>> {
+        let key = tags.get(KEY_PROP).unwrap_or_default().to_owned();
+        self.calls.borrow_mut().push(LoaderCall::Load(key.clone()));
+        if self.fail.get() {
+            return Err(Error::ModuleLoad);
+        }
+        // Handle owns the tunnel key instead of a module, it is taken back on unload
+        Ok(NonNull::from(Box::leak(Box::new(key))).cast())
+    }
+
+    fn unload(&self, module: NonNull<pw_impl_module>) {
+        let key = unsafe { Box::from_raw(module.cast::<String>().as_ptr()) };
+        self.calls.borrow_mut().push(LoaderCall::Unload(*key));
+    }
+}
+
+/// Creates nothing, only remembers names of the sinks it was asked for
+#[derive(Default)]
+pub(crate) struct NoopBackend {
+    pub names: RefCell<Vec<String>>,
+}
+
+impl SinkBackend for NoopBackend {
+    fn load(
+        &self,
+        _opts: &Opts,
+        _msg: &Discovered,
+        name: &str,
+        tags: Properties,
+    ) -> Result<ModuleHandle> {
+        self.names.borrow_mut().push(name.to_owned());
+        let loader: Rc<dyn ModuleLoader> = Rc::new(RecordingLoader::default());
+        ModuleHandle::load(&loader, Properties::new(), tags)
+    }
+}
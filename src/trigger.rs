@@ -0,0 +1,31 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// Wakes discovery threads sleeping between scans
+///
+/// Every trigger bumps the generation, so each thread runs one extra scan,
+/// no matter whether it was sleeping or scanning when triggered
+#[derive(Clone, Default)]
+pub(crate) struct ScanTrigger(Arc<(Mutex<u64>, Condvar)>);
+
+impl ScanTrigger {
+    pub(crate) fn trigger(&self) {
+        let (generation, condvar) = &*self.0;
+        *generation.lock().expect("not poisoned") += 1;
+        condvar.notify_all();
+    }
+
+    /// Sleep for the interval, or until triggered
+    ///
+    /// `seen` is the generation handled by the caller, it starts at 0
+    pub(crate) fn wait(&self, seen: &mut u64, interval: Duration) {
+        let (generation, condvar) = &*self.0;
+        let guard = generation.lock().expect("not poisoned");
+        let (guard, _timeout) = condvar
+            .wait_timeout_while(guard, interval, |generation| *generation == *seen)
+            .expect("not poisoned");
+        *seen = *guard;
+    }
+}
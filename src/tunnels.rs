@@ -0,0 +1,965 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use pipewire::{properties, spa::WritableDict};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    addr::{family_name, interface_display},
+    airplay::{AudioFlags, Encryption, Features, Group, Metadata, ServerVersion, StatusFlags},
+    backend::SinkBackend,
+    config::Config,
+    cooldown::{Backoff, Cooldown},
+    existing::ExistingSinks,
+    failures::Failures,
+    opts::Opts,
+    pattern::name_matches,
+    template::NameVars,
+    txt::parse_bool_txt,
+    Discovered, Event, Tunnel, TunnelKey, TunnelState, KEY_PROP, MANAGED_PROP, SERVICE_PROP,
+};
+
+/// Name of the sink, from --name-template, or readable device name
+fn sink_name(msg: &Discovered, opts: &Opts) -> String {
+    let key = msg.key();
+    let readable_name = msg.readable_name(&opts.unnamed);
+    if msg.name().is_none() {
+        info!("device {key:?} doesn't advertise its name, using {readable_name:?}");
+    }
+    opts.name_template
+        .as_ref()
+        .and_then(|template| {
+            template
+                .expand(&NameVars {
+                    name: &readable_name,
+                    model: msg.model(),
+                    host: &msg.hostname,
+                    family: family_name(&msg.socket),
+                })
+                .or_else(|| {
+                    warn!("name template expanded to empty string for {key:?}, using default");
+                    None
+                })
+        })
+        .unwrap_or_else(|| {
+            let mut name = readable_name.clone();
+            if msg.socket.is_ipv4() {
+                name.push_str(" (IPv4)");
+            }
+            name
+        })
+}
+
+/// Identical devices (same model and user-assigned name) would have sinks with the
+/// same name, they are told apart by the end of the address
+fn unique_name(tunnels: &HashMap<TunnelKey, Tunnel>, key: &TunnelKey, name: String) -> String {
+    let taken = |name: &str| {
+        tunnels
+            .iter()
+            .any(|(k, t)| k != key && t.name.as_deref() == Some(name))
+    };
+    if !taken(&name) {
+        return name;
+    }
+    let suffix = match key.socket.ip() {
+        IpAddr::V4(ip) => ip.octets()[3].to_string(),
+        IpAddr::V6(ip) => format!("{:x}", ip.segments()[7]),
+    };
+    let mut unique = format!("{name} ({suffix})");
+    let mut counter = 2;
+    while taken(&unique) {
+        unique = format!("{name} ({suffix}, {counter})");
+        counter += 1;
+    }
+    info!("sink name {name:?} is already taken, using {unique:?} for {key:?}");
+    unique
+}
+
+/// Whether a module should be loaded for the tunnel, the only thing
+/// preventing duplicate sinks for the same device
+fn needs_load(tunnels: &HashMap<TunnelKey, Tunnel>, key: &TunnelKey) -> bool {
+    match tunnels.get(key).map(|t| &t.state) {
+        Some(TunnelState::Loaded(_) | TunnelState::Connecting(..) | TunnelState::Unsupported) => {
+            false
+        }
+        Some(TunnelState::Loading) => {
+            debug!("tunnel is already loading: {key:?}");
+            false
+        }
+        Some(TunnelState::Failed) => {
+            debug!("retrying failed tunnel: {key:?}");
+            true
+        }
+        None => true,
+    }
+}
+
+fn add_tunnel(
+    opts: &Opts,
+    backend: &dyn SinkBackend,
+    tunnels: &RefCell<HashMap<TunnelKey, Tunnel>>,
+    existing: &ExistingSinks,
+    failures: &Failures,
+    msg: Discovered,
+) {
+    let key = msg.key();
+    if !needs_load(&tunnels.borrow(), &key) {
+        return;
+    }
+    if let Some(id) = existing.find(&key) {
+        debug!("sink for tunnel {key:?} already exists in the graph (node {id}), skipping");
+        return;
+    }
+    if msg.txt("et").and_then(|et| Encryption::from_et(et, false)) == Some(Encryption::FairPlay) {
+        warn!("ignoring tunnel {key:?}: device only supports FairPlay encryption, which isn't implemented by raop-sink");
+        tunnels.borrow_mut().insert(
+            key,
+            Tunnel {
+                domain: msg.domain.clone(),
+                pinned: false,
+                group: None,
+                name: None,
+                ifindex: msg.ifindex,
+                version: msg.txt("vs").map(str::to_owned),
+                metadata: msg.txt("md").map(Metadata::from_md),
+                audio: AudioFlags::from_txt(&msg.txt),
+                state: TunnelState::Unsupported,
+            },
+        );
+        return;
+    }
+    let status = match msg.txt("sf") {
+        Some(sf) => StatusFlags::parse(sf).unwrap_or_else(|e| {
+            warn!("invalid status flags {sf:?}: {e}");
+            StatusFlags::default()
+        }),
+        None => StatusFlags::default(),
+    };
+    if status.is_busy() {
+        // Loaded module would fail to connect anyway, device will be
+        // rediscovered on the next scan
+        failures.report(&key, format!("device is busy ({status:?}), deferring"));
+        return;
+    }
+    if msg.txt("pw").is_some_and(parse_bool_txt) || status.password_required() {
+        warn!("tunnel {key:?} requires password, which can't be configured, connection will likely fail");
+    }
+    // Static devices rely on module defaults
+    if msg.domain.is_some() {
+        if let Some(field) = msg.missing_field() {
+            failures.report(&key, format!("advertisement is missing {field}, deferring"));
+            return;
+        }
+    }
+    let version = msg.txt("vs").and_then(|vs| {
+        ServerVersion::parse(vs)
+            .map_err(|e| warn!("invalid server version {vs:?}: {e}"))
+            .ok()
+    });
+    let features = msg
+        .txt("ft")
+        .and_then(|ft| {
+            Features::parse(ft)
+                .map_err(|e| warn!("invalid features {ft:?}: {e}"))
+                .ok()
+        })
+        .unwrap_or_default();
+    if let Some(issue) = version.as_ref().and_then(ServerVersion::known_issue) {
+        warn!("tunnel {key:?} reports version which is known to be problematic: {issue}");
+    }
+    let model = msg.model();
+    let readable_name = msg.readable_name(&opts.unnamed);
+    let pinned = opts
+        .pin
+        .iter()
+        .any(|p| name_matches(p, &readable_name) || model.is_some_and(|m| name_matches(p, m)));
+    let mut tags = properties! {
+        MANAGED_PROP => "true",
+        KEY_PROP => key.to_string(),
+    };
+    if let Some(canonical) = &msg.canonical {
+        tags.insert(SERVICE_PROP, canonical.as_str());
+    }
+    // Stereo pairs/multi-room groups, so session manager may treat members as one device
+    let group = Group::from_txt(&msg.txt);
+    if let Some(group) = &group {
+        tags.insert("device.group", group.id.as_str());
+    }
+    let base_name = sink_name(&msg, opts);
+    let name = unique_name(&tunnels.borrow(), &key, base_name.clone());
+    if name != base_name {
+        tags.insert("node.description", name.as_str());
+    }
+    let tunnel = |state| Tunnel {
+        domain: msg.domain.clone(),
+        pinned,
+        group: group.as_ref().map(|g| g.id.clone()),
+        name: Some(name.clone()),
+        ifindex: msg.ifindex,
+        version: msg.txt("vs").map(str::to_owned),
+        metadata: msg.txt("md").map(Metadata::from_md),
+        audio: AudioFlags::from_txt(&msg.txt),
+        state,
+    };
+    tunnels
+        .borrow_mut()
+        .insert(key.clone(), tunnel(TunnelState::Loading));
+    let state = match backend.load(opts, &msg, &name, tags) {
+        Ok(module) => {
+            info!(
+            "discovered new tunnel: {key:?} via {} on {} ({status:?}), version {}, features {features:?}",
+            family_name(&key.socket),
+            interface_display(msg.ifindex),
+            version
+                .as_ref()
+                .map_or_else(|| "<unknown>".to_owned(), |v| v.to_string()),
+        );
+            if let Some(group) = &group {
+                let members: Vec<_> = tunnels
+                    .borrow()
+                    .iter()
+                    .filter(|(k, t)| **k != key && t.group.as_ref() == Some(&group.id))
+                    .map(|(k, _)| k.to_string())
+                    .collect();
+                info!(
+                    "tunnel {key:?} is a member of group {} ({}), other members: {members:?}",
+                    group.id,
+                    group.name.as_deref().unwrap_or("<unnamed>")
+                );
+            }
+            if pinned {
+                info!("tunnel {key:?} is pinned, it will be kept until exit");
+            }
+            failures.clear(&key);
+            if opts.connect_timeout != 0 {
+                TunnelState::Connecting(module, Instant::now())
+            } else {
+                TunnelState::Loaded(module)
+            }
+        }
+        Err(e) => {
+            failures.report(&key, format!("failed to load module: {e}"));
+            TunnelState::Failed
+        }
+    };
+    tunnels.borrow_mut().insert(key, tunnel(state));
+}
+
+/// Unload tunnels of disappeared service instance, except the pinned ones
+///
+/// Modules are unloaded by dropping removed tunnels, keys of removed tunnels are returned
+fn remove_tunnels(tunnels: &RefCell<HashMap<TunnelKey, Tunnel>>, domain: &str) -> Vec<TunnelKey> {
+    let mut removed = Vec::new();
+    tunnels.borrow_mut().retain(|key, tunnel| {
+        if tunnel.domain.as_deref() != Some(domain) {
+            return true;
+        }
+        if tunnel.pinned {
+            info!("keeping pinned tunnel: {key:?}");
+            return true;
+        }
+        info!("removed tunnel: {key:?}");
+        removed.push(key.clone());
+        false
+    });
+    removed
+}
+
+/// Re-read config, applying changes to static devices. Other settings are
+/// passed on the command line, and require restart
+fn reload_config(
+    opts: &Opts,
+    backend: &dyn SinkBackend,
+    tunnels: &RefCell<HashMap<TunnelKey, Tunnel>>,
+    existing: &ExistingSinks,
+    failures: &Failures,
+    config: &RefCell<Config>,
+) {
+    let Some(path) = &opts.config else {
+        warn!("received SIGHUP, but --config is not set, nothing to reload");
+        return;
+    };
+    let new = match Config::load(path) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("failed to reload config, keeping the current one: {e}");
+            return;
+        }
+    };
+    let old = config.replace(new);
+    let config = config.borrow();
+    for device in &old.static_devices {
+        if config.static_devices.contains(device) {
+            continue;
+        }
+        info!("static device {} was removed from config", device.name);
+        let key = device.discovered().key();
+        let mut tunnels = tunnels.borrow_mut();
+        if tunnels.get(&key).is_some_and(|t| t.domain.is_none()) {
+            tunnels.remove(&key);
+            existing.unloaded(&key);
+            info!("removed tunnel: {key:?}");
+        }
+    }
+    for device in &config.static_devices {
+        if old.static_devices.contains(device) {
+            continue;
+        }
+        info!("static device {} was added to config", device.name);
+        add_tunnel(
+            opts,
+            backend,
+            tunnels,
+            existing,
+            failures,
+            device.discovered(),
+        );
+    }
+    if old.resolve != config.resolve {
+        warn!("resolve flags were changed, restart is required to apply them");
+    }
+    info!("config reloaded");
+}
+
+/// State shared by main loop callbacks
+pub(crate) struct Daemon {
+    pub opts: Opts,
+    pub backend: Box<dyn SinkBackend>,
+    pub tunnels: RefCell<HashMap<TunnelKey, Tunnel>>,
+    pub existing: ExistingSinks,
+    pub failures: Failures,
+    pub cooldown: Cooldown,
+    /// Devices which sinks timed out, with --connect-timeout
+    pub connect_backoff: Backoff,
+    /// Shared with the backend, which reads codec latency from it
+    pub config: Rc<RefCell<Config>>,
+    /// Devices waiting to be loaded, when loads are throttled by --load-rate
+    pub pending: RefCell<VecDeque<Discovered>>,
+}
+
+impl Daemon {
+    pub(crate) fn handle(&self, event: Event) {
+        match event {
+            Event::Discovered(msg) => {
+                let key = msg.key();
+                if !self.cooldown.allows(&key) || !self.connect_backoff.allows(&key) {
+                    return;
+                }
+                if self.opts.load_rate != 0 {
+                    let mut pending = self.pending.borrow_mut();
+                    if needs_load(&self.tunnels.borrow(), &key)
+                        && !pending.iter().any(|p| p.key() == key)
+                    {
+                        pending.push_back(msg);
+                    }
+                    return;
+                }
+                add_tunnel(
+                    &self.opts,
+                    &*self.backend,
+                    &self.tunnels,
+                    &self.existing,
+                    &self.failures,
+                    msg,
+                )
+            }
+            Event::Removed(domain) => {
+                // Device is gone, queued load would only fail
+                self.pending
+                    .borrow_mut()
+                    .retain(|p| p.domain.as_deref() != Some(domain.as_str()));
+                for key in remove_tunnels(&self.tunnels, &domain) {
+                    self.existing.unloaded(&key);
+                    self.cooldown.removed(key);
+                }
+            }
+        }
+    }
+
+    /// Tunnel table, one tunnel per line
+    /// `key=value` pairs, `scan` line followed by a line per tunnel, see README
+    pub(crate) fn status(&self, since_scan: Duration) -> String {
+        // Strings may contain spaces, they are quoted
+        let quoted = |v: Option<&str>| v.map_or_else(|| "-".to_owned(), |v| format!("{v:?}"));
+        let mut failures = self.failures.summaries();
+        let tunnels = self.tunnels.borrow();
+        let mut lines: Vec<_> = tunnels
+            .iter()
+            .map(|(key, tunnel)| {
+                let state = match tunnel.state {
+                    TunnelState::Loading => "loading",
+                    TunnelState::Loaded(_) => "loaded",
+                    TunnelState::Connecting(..) => "connecting",
+                    TunnelState::Failed => "failed",
+                    TunnelState::Unsupported => "unsupported",
+                };
+                let (failure, count) = failures.remove(key).unzip();
+                format!(
+                    "tunnel key={key} state={state} pinned={} name={} domain={} ifindex={} vs={} md={} {} failures={} failure={}\n",
+                    tunnel.pinned,
+                    quoted(tunnel.name.as_deref()),
+                    quoted(tunnel.domain.as_deref()),
+                    tunnel.ifindex,
+                    quoted(tunnel.version.as_deref()),
+                    tunnel.metadata.map_or_else(|| "-".to_owned(), |m| m.to_string()),
+                    tunnel.audio,
+                    count.unwrap_or(0),
+                    quoted(failure.as_deref()),
+                )
+            })
+            .collect();
+        // Devices which failed before tunnel was created, i.e busy ones
+        lines.extend(failures.into_iter().map(|(key, (failure, count))| {
+            format!(
+                "tunnel key={key} state=deferred failures={count} failure={}\n",
+                quoted(Some(&failure)),
+            )
+        }));
+        lines.sort();
+        format!(
+            "scan since_success={}s\n{}",
+            since_scan.as_secs(),
+            lines.concat()
+        )
+    }
+
+    /// Unload sinks which didn't appear in the graph within `--connect-timeout`,
+    /// i.e device still advertises itself, but is unreachable
+    pub(crate) fn check_connecting(&self) {
+        let timeout = Duration::from_secs(self.opts.connect_timeout);
+        let mut tunnels = self.tunnels.borrow_mut();
+        for (key, tunnel) in tunnels.iter_mut() {
+            // Module is unloaded when replaced state is dropped
+            tunnel.state = match std::mem::replace(&mut tunnel.state, TunnelState::Failed) {
+                TunnelState::Connecting(module, since) if self.existing.find(key).is_some() => {
+                    debug!(
+                        "sink for tunnel {key:?} appeared after {:?}",
+                        since.elapsed()
+                    );
+                    TunnelState::Loaded(module)
+                }
+                TunnelState::Connecting(_, since) if since.elapsed() >= timeout => {
+                    self.failures.report(
+                        key,
+                        format!("sink didn't appear within {timeout:?}, unloading"),
+                    );
+                    self.connect_backoff.failed(key.clone());
+                    TunnelState::Failed
+                }
+                state => state,
+            };
+        }
+    }
+
+    /// Load the next throttled device
+    pub(crate) fn load_next(&self) {
+        let Some(msg) = self.pending.borrow_mut().pop_front() else {
+            return;
+        };
+        add_tunnel(
+            &self.opts,
+            &*self.backend,
+            &self.tunnels,
+            &self.existing,
+            &self.failures,
+            msg,
+        );
+    }
+
+    /// Recreate all sinks. Discovered devices are re-added on the next scan, as
+    /// every scan reports all of them, and tunnels are only touched on the main loop
+    pub(crate) fn refresh(&self) {
+        info!("refreshing all sinks");
+        let tunnels = self.tunnels.take();
+        let keys: Vec<_> = tunnels.keys().cloned().collect();
+        drop(tunnels);
+        // Registry would only report removal of the nodes later, static devices
+        // are recreated right away
+        for key in &keys {
+            self.existing.unloaded(key);
+        }
+        // Discovered devices are queued again on the next scan
+        self.pending.borrow_mut().clear();
+        info!("unloaded {} tunnels, waiting for the next scan", keys.len());
+        for device in &self.config.borrow().static_devices {
+            self.handle(Event::Discovered(device.discovered()));
+        }
+    }
+
+    pub(crate) fn reload(&self) {
+        let latency = self.config.borrow().latency;
+        reload_config(
+            &self.opts,
+            &*self.backend,
+            &self.tunnels,
+            &self.existing,
+            &self.failures,
+            &self.config,
+        );
+        // Latency is only read when module is loaded
+        if self.config.borrow().latency != latency {
+            info!("codec latency was changed");
+            self.refresh();
+        }
+    }
+}
+
+/// Unload all modules, forcing exit if it takes longer than `timeout`,
+/// i.e if pipewire itself is shutting down
+pub(crate) fn unload_tunnels(tunnels: HashMap<TunnelKey, Tunnel>, timeout: Duration) {
+    let pending = Arc::new(Mutex::new(tunnels.keys().cloned().collect::<Vec<_>>()));
+    std::thread::spawn({
+        let pending = pending.clone();
+        move || {
+            std::thread::sleep(timeout);
+            let pending = pending.lock().expect("not poisoned");
+            error!("timed out unloading modules, remaining tunnels: {pending:?}");
+            std::process::exit(1);
+        }
+    });
+    for (key, tunnel) in tunnels {
+        drop(tunnel);
+        debug!("unloaded tunnel: {key:?}");
+        pending.lock().expect("not poisoned").retain(|k| *k != key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use pipewire::Properties;
+
+    use super::*;
+    use crate::{
+        backend::RaopBackend,
+        loader::ModuleHandle,
+        testing::{discovered, opts, with_txt, LoaderCall, NoopBackend, RecordingLoader},
+        Result,
+    };
+
+    fn daemon(args: &[&str], config: Config) -> (Daemon, Rc<RecordingLoader>) {
+        let loader = Rc::new(RecordingLoader::default());
+        let config = Rc::new(RefCell::new(config));
+        let daemon = Daemon {
+            opts: opts(args),
+            backend: Box::new(RaopBackend {
+                loader: loader.clone(),
+                config: config.clone(),
+            }),
+            tunnels: RefCell::default(),
+            existing: ExistingSinks::detached(),
+            failures: Failures::default(),
+            cooldown: Cooldown::new(Duration::ZERO),
+            connect_backoff: Backoff::new(Duration::ZERO),
+            config,
+            pending: RefCell::default(),
+        };
+        (daemon, loader)
+    }
+
+    fn static_config() -> Config {
+        toml::from_str(
+            r#"
+[[static]]
+address = "192.168.1.2"
+port = 7000
+name = "A"
+"#,
+        )
+        .unwrap()
+    }
+
+    /// Config file, removed on drop
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!(
+                "pw-resolved-discover-{}-{name}.toml",
+                std::process::id()
+            )))
+        }
+
+        fn write(&self, data: &str) {
+            std::fs::write(&self.0, data).unwrap();
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn add_tunnel_loads_once() {
+        let (daemon, loader) = daemon(&[], Config::default());
+        let msg = || discovered("a.local", "192.168.1.2:7000", "A._raop._tcp.local");
+        daemon.handle(Event::Discovered(msg()));
+        daemon.handle(Event::Discovered(msg()));
+        assert_eq!(
+            loader.calls.take(),
+            [LoaderCall::Load("a.local:192.168.1.2:7000".to_owned())]
+        );
+    }
+
+    #[test]
+    fn remove_tunnels_unloads_domain() {
+        let (daemon, loader) = daemon(&[], Config::default());
+        for (hostname, socket, domain) in [
+            ("a.local", "192.168.1.2:7000", "A._raop._tcp.local"),
+            ("a.local", "[2001:db8::2]:7000", "A._raop._tcp.local"),
+            ("b.local", "192.168.1.3:7000", "B._raop._tcp.local"),
+        ] {
+            daemon.handle(Event::Discovered(discovered(hostname, socket, domain)));
+        }
+        loader.calls.take();
+
+        let mut removed: Vec<_> = remove_tunnels(&daemon.tunnels, "A._raop._tcp.local")
+            .iter()
+            .map(TunnelKey::to_string)
+            .collect();
+        removed.sort();
+        assert_eq!(
+            removed,
+            ["a.local:192.168.1.2:7000", "a.local:[2001:db8::2]:7000"]
+        );
+        let mut calls = loader.calls.take();
+        calls.sort_by_key(|c| format!("{c:?}"));
+        assert_eq!(
+            calls,
+            [
+                LoaderCall::Unload("a.local:192.168.1.2:7000".to_owned()),
+                LoaderCall::Unload("a.local:[2001:db8::2]:7000".to_owned()),
+            ]
+        );
+        assert_eq!(daemon.tunnels.borrow().len(), 1);
+    }
+
+    #[test]
+    fn remove_tunnels_keeps_pinned() {
+        let (daemon, loader) = daemon(&["--pin", "A"], Config::default());
+        daemon.handle(Event::Discovered(discovered(
+            "a.local",
+            "192.168.1.2:7000",
+            "A._raop._tcp.local",
+        )));
+        loader.calls.take();
+        assert!(remove_tunnels(&daemon.tunnels, "A._raop._tcp.local").is_empty());
+        assert_eq!(daemon.tunnels.borrow().len(), 1);
+        assert_eq!(loader.calls.take(), []);
+    }
+
+    #[test]
+    fn failed_load_is_retried() {
+        let (daemon, loader) = daemon(&[], Config::default());
+        let msg = || discovered("a.local", "192.168.1.2:7000", "A._raop._tcp.local");
+        loader.fail.set(true);
+        daemon.handle(Event::Discovered(msg()));
+        loader.fail.set(false);
+        daemon.handle(Event::Discovered(msg()));
+        daemon.handle(Event::Discovered(msg()));
+        let load = LoaderCall::Load("a.local:192.168.1.2:7000".to_owned());
+        assert_eq!(loader.calls.take(), [load.clone(), load]);
+        assert!(matches!(
+            daemon.tunnels.borrow().values().next().unwrap().state,
+            TunnelState::Loaded(_)
+        ));
+    }
+
+    #[test]
+    fn add_tunnel_skips_existing_sink() {
+        let (daemon, loader) = daemon(&[], Config::default());
+        daemon
+            .existing
+            .insert(10, "raop_sink.a.local.192.168.1.2.7000", None);
+        daemon.handle(Event::Discovered(discovered(
+            "a.local",
+            "192.168.1.2:7000",
+            "A._raop._tcp.local",
+        )));
+        assert_eq!(loader.calls.take(), []);
+    }
+
+    #[test]
+    fn tunnel_keys() {
+        let (daemon, loader) = daemon(&[], Config::default());
+        for (hostname, socket) in [
+            ("a.local", "192.168.1.2:7000"),
+            ("a.local", "192.168.1.2:7000"),
+            // Same host, other port
+            ("a.local", "192.168.1.2:7001"),
+            // Same address and port, other host
+            ("b.local", "192.168.1.2:7000"),
+            ("a.local", "192.168.1.2:7001"),
+        ] {
+            daemon.handle(Event::Discovered(discovered(
+                hostname,
+                socket,
+                "A._raop._tcp.local",
+            )));
+        }
+        let mut keys: Vec<_> = daemon
+            .tunnels
+            .borrow()
+            .keys()
+            .map(|k| k.to_string())
+            .collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "a.local:192.168.1.2:7000",
+                "a.local:192.168.1.2:7001",
+                "b.local:192.168.1.2:7000"
+            ]
+        );
+        let loads: Vec<_> = loader
+            .calls
+            .take()
+            .into_iter()
+            .map(|c| match c {
+                LoaderCall::Load(key) => key,
+                LoaderCall::Unload(key) => panic!("unexpected unload of {key}"),
+            })
+            .collect();
+        assert_eq!(
+            loads,
+            [
+                "a.local:192.168.1.2:7000",
+                "a.local:192.168.1.2:7001",
+                "b.local:192.168.1.2:7000"
+            ]
+        );
+    }
+
+    #[test]
+    fn unique_names() {
+        let backend = NoopBackend::default();
+        let (daemon, _) = daemon(&["--name-template", "{name}"], Config::default());
+        let add = |hostname: &str, socket: &str| {
+            add_tunnel(
+                &daemon.opts,
+                &backend,
+                &daemon.tunnels,
+                &daemon.existing,
+                &daemon.failures,
+                discovered(hostname, socket, "Speaker._raop._tcp.local"),
+            )
+        };
+        add("a.local", "192.168.1.2:7000");
+        add("b.local", "192.168.1.3:7000");
+        add("c.local", "[2001:db8::1:ab]:7000");
+        add("d.local", "192.168.2.3:7000");
+        // Same tunnel keeps its name
+        add("a.local", "192.168.1.2:7000");
+        assert_eq!(
+            *backend.names.borrow(),
+            ["Speaker", "Speaker (3)", "Speaker (ab)", "Speaker (3, 2)"]
+        );
+        let tunnels = daemon.tunnels.borrow();
+        let key = discovered("a.local", "192.168.1.2:7000", "").key();
+        assert_eq!(unique_name(&tunnels, &key, "Speaker".to_owned()), "Speaker");
+    }
+
+    #[test]
+    fn reload_config_recreates_changed_device() {
+        let file = ConfigFile::new("reload");
+        let device = |codec| {
+            format!(
+                r#"
+[[static]]
+address = "192.168.1.2"
+port = 7000
+name = "A"
+codec = "{codec}"
+"#
+            )
+        };
+        file.write(&device("ALAC"));
+        let (daemon, loader) = daemon(
+            &["--config", file.0.to_str().unwrap()],
+            Config::load(&file.0).unwrap(),
+        );
+        let msg = daemon.config.borrow().static_devices[0].discovered();
+        let key = msg.key().to_string();
+        daemon.handle(Event::Discovered(msg));
+        // Sink of the loaded module appears in the graph
+        daemon
+            .existing
+            .insert(10, "raop_sink.192.168.1.2.192.168.1.2.7000", Some(&key));
+
+        file.write(&device("AAC"));
+        daemon.reload();
+        assert_eq!(
+            loader.calls.take(),
+            [
+                LoaderCall::Load(key.clone()),
+                LoaderCall::Unload(key.clone()),
+                LoaderCall::Load(key),
+            ]
+        );
+    }
+
+    #[test]
+    fn reload_recreates_sinks_on_latency_change() {
+        let file = ConfigFile::new("latency");
+        file.write("[latency]\nALAC = 2000\n");
+        let (daemon, loader) = daemon(
+            &["--config", file.0.to_str().unwrap()],
+            Config::load(&file.0).unwrap(),
+        );
+        let msg = discovered("a.local", "192.168.1.2:7000", "A._raop._tcp.local");
+        let key = msg.key().to_string();
+        daemon.handle(Event::Discovered(msg));
+        // Unchanged config
+        daemon.reload();
+        assert_eq!(loader.calls.take(), [LoaderCall::Load(key.clone())]);
+        file.write("[latency]\nALAC = 2500\n");
+        daemon.reload();
+        assert_eq!(loader.calls.take(), [LoaderCall::Unload(key)]);
+        assert_eq!(daemon.config.borrow().latency.alac, 2500);
+    }
+
+    #[test]
+    fn status() {
+        let (daemon, loader) = daemon(&[], Config::default());
+        daemon.handle(Event::Discovered(with_txt(
+            discovered("a.local", "192.168.1.2:7000", "A Room._raop._tcp.local"),
+            &["cn=0,1", "et=0", "vs=366.0", "md=0,2", "da=true"],
+        )));
+        loader.fail.set(true);
+        daemon.handle(Event::Discovered(discovered(
+            "b.local",
+            "192.168.1.3:7000",
+            "B._raop._tcp.local",
+        )));
+        daemon.handle(Event::Discovered(with_txt(
+            discovered("c.local", "192.168.1.4:7000", "C._raop._tcp.local"),
+            &["cn=0,1", "sf=0x20000"],
+        )));
+        let status = daemon.status(Duration::from_secs(3));
+        let lines: Vec<_> = status.lines().collect();
+        assert_eq!(lines[0], "scan since_success=3s");
+        assert_eq!(
+            lines[1],
+            r#"tunnel key=a.local:192.168.1.2:7000 state=loaded pinned=false name="A Room (IPv4)" domain="A Room._raop._tcp.local" ifindex=2 vs="366.0" md=text,progress da=true sv=- failures=0 failure=-"#
+        );
+        assert!(lines[2].starts_with(
+            "tunnel key=b.local:192.168.1.3:7000 state=failed pinned=false name=\"B (IPv4)\" domain=\"B._raop._tcp.local\" ifindex=2 vs=- md=- da=- sv=- failures=1 failure=\"failed to load module: "
+        ));
+        assert!(lines[3].starts_with(
+            "tunnel key=c.local:192.168.1.4:7000 state=deferred failures=1 failure=\"device is busy"
+        ));
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn refresh_recreates_static_devices() {
+        let (daemon, loader) = daemon(&[], static_config());
+        let msg = daemon.config.borrow().static_devices[0].discovered();
+        let key = msg.key().to_string();
+        daemon.handle(Event::Discovered(msg));
+        // Sink of the loaded module appears in the graph
+        daemon
+            .existing
+            .insert(10, "raop_sink.192.168.1.2.192.168.1.2.7000", Some(&key));
+        daemon.refresh();
+        assert_eq!(
+            loader.calls.take(),
+            [
+                LoaderCall::Load(key.clone()),
+                LoaderCall::Unload(key.clone()),
+                LoaderCall::Load(key),
+            ]
+        );
+    }
+
+    #[test]
+    fn removal_clears_pending() {
+        let (daemon, loader) = daemon(&["--load-rate", "1000"], Config::default());
+        for (hostname, domain) in [
+            ("a.local", "A._raop._tcp.local"),
+            ("b.local", "B._raop._tcp.local"),
+        ] {
+            daemon.handle(Event::Discovered(discovered(
+                hostname,
+                "192.168.1.2:7000",
+                domain,
+            )));
+        }
+        daemon.handle(Event::Removed("A._raop._tcp.local".to_owned()));
+        daemon.load_next();
+        daemon.load_next();
+        assert_eq!(
+            loader.calls.take(),
+            [LoaderCall::Load("b.local:192.168.1.2:7000".to_owned())]
+        );
+    }
+
+    #[test]
+    fn refresh_clears_pending() {
+        let (daemon, loader) = daemon(&["--load-rate", "1000"], Config::default());
+        daemon.handle(Event::Discovered(discovered(
+            "a.local",
+            "192.168.1.2:7000",
+            "A._raop._tcp.local",
+        )));
+        assert_eq!(daemon.pending.borrow().len(), 1);
+        daemon.refresh();
+        assert!(daemon.pending.borrow().is_empty());
+        daemon.load_next();
+        assert_eq!(loader.calls.take(), []);
+    }
+
+    /// Backend discovering the same device again while its module is loading,
+    /// as happens when main loop is iterated during the load
+    struct Reentrant<'a> {
+        daemon: &'a Daemon,
+        inner: NoopBackend,
+    }
+
+    impl SinkBackend for Reentrant<'_> {
+        fn load(
+            &self,
+            opts: &Opts,
+            msg: &Discovered,
+            name: &str,
+            tags: Properties,
+        ) -> Result<ModuleHandle> {
+            let d = self.daemon;
+            let again = discovered(
+                &msg.hostname,
+                &msg.socket.to_string(),
+                msg.domain.as_deref().unwrap(),
+            );
+            add_tunnel(opts, self, &d.tunnels, &d.existing, &d.failures, again);
+            self.inner.load(opts, msg, name, tags)
+        }
+    }
+
+    #[test]
+    fn loading_tunnel_is_not_loaded_again() {
+        let (daemon, _) = daemon(&[], Config::default());
+        let msg = discovered("a.local", "192.168.1.2:7000", "A._raop._tcp.local");
+        assert!(needs_load(&daemon.tunnels.borrow(), &msg.key()));
+        let backend = Reentrant {
+            daemon: &daemon,
+            inner: NoopBackend::default(),
+        };
+        let d = &daemon;
+        add_tunnel(&d.opts, &backend, &d.tunnels, &d.existing, &d.failures, msg);
+        assert_eq!(*backend.inner.names.borrow(), ["A (IPv4)"]);
+        assert!(!needs_load(
+            &daemon.tunnels.borrow(),
+            &discovered("a.local", "192.168.1.2:7000", "A._raop._tcp.local").key()
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+/// DNS-SD TXT records of the service, RFC 6763 6.3-6.5
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Txt {
+    records: Vec<String>,
+    values: HashMap<String, String>,
+}
+
+impl Txt {
+    pub(crate) fn new(records: Vec<String>) -> Self {
+        let mut values = HashMap::new();
+        for record in &records {
+            // Key without `=` is a boolean attribute, value might contain `=` itself
+            let (key, value) = record.split_once('=').unwrap_or((record, ""));
+            let key = key.trim().to_ascii_lowercase();
+            // Records without key are ignored, and only the first occurence of the key is used
+            if key.is_empty() || values.contains_key(&key) {
+                continue;
+            }
+            values.insert(key, value.trim().to_owned());
+        }
+        Self { records, values }
+    }
+
+    /// Value of the key, keys are case-insensitive
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .get(&key.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Records as advertised
+    pub(crate) fn records(&self) -> &[String] {
+        &self.records
+    }
+}
+
+/// Split comma-separated list value, i.e `cn=0,1`
+///
+/// Items are trimmed, as some firmwares put spaces after commas, and
+/// double-quoted items may contain commas
+pub(crate) fn split_list(value: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&value[start..]);
+    items
+        .into_iter()
+        .map(|i| i.trim().trim_matches('"'))
+        .collect()
+}
+
+/// Boolean value, vendors use `true`, `yes` or `1` (case-insensitive) for truthy values,
+/// everything else, including empty value, is false
+pub(crate) fn parse_bool_txt(value: &str) -> bool {
+    let value = value.trim();
+    ["true", "yes", "1"]
+        .into_iter()
+        .any(|v| value.eq_ignore_ascii_case(v))
+}
+
+/// Comma-separated list value contains the item
+pub(crate) fn list_contains(value: &str, item: &str) -> bool {
+    split_list(value).contains(&item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bool_txt() {
+        for value in ["true", "TRUE", "yes", "Yes", "1", " true "] {
+            assert!(parse_bool_txt(value), "{value:?}");
+        }
+        for value in ["false", "no", "0", "", " ", "2", "truee", "on"] {
+            assert!(!parse_bool_txt(value), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn boolean_attribute() {
+        // Key without `=` has empty value, which is false
+        let txt = Txt::new(vec!["pw".to_owned(), "PW=true".to_owned()]);
+        assert_eq!(txt.get("pw"), Some(""));
+        assert!(!txt.get("pw").is_some_and(parse_bool_txt));
+    }
+
+    #[test]
+    fn split_list_trims() {
+        assert_eq!(split_list("0, 1 ,2"), ["0", "1", "2"]);
+        assert_eq!(split_list(" 0 "), ["0"]);
+        assert_eq!(split_list(r#"a, "b, c""#), ["a", "b, c"]);
+    }
+
+    #[test]
+    fn list_contains_padded() {
+        assert!(list_contains("0, 1", "1"));
+        assert!(list_contains(" 0,1", "0"));
+        assert!(!list_contains("0, 1", " 1"));
+        assert!(!list_contains("0,1", "2"));
+    }
+}
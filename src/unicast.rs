@@ -0,0 +1,267 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::{Hash, Hasher},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use pipewire::channel::{self, Receiver, Sender};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    addr::{socket_addr, FamilyPreference},
+    airplay::instance_name,
+    dns::{
+        encode_query, parse_message, RData, Record, FLAG_RD, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA,
+        TYPE_SRV, TYPE_TXT,
+    },
+    ratelimit::warn_limited,
+    rr::{select_srv, strip_name_suffix},
+    trigger::ScanTrigger,
+    txt::Txt,
+    Discovered, Error, Event, KnownHosts, ResolvedHost, Result, AF_INET4, AF_INET6, CLASS_IN,
+    REMOVAL_RETRIES, SCAN_INTERVAL, SERVICE, TYPE_PTR,
+};
+
+/// Source of DNS-SD records, queried directly instead of through resolve1
+pub(crate) trait Querier {
+    /// Records of all response sections, empty if name doesn't exist
+    fn query(&mut self, name: &str, type_: u16) -> Result<Vec<Record>>;
+
+    /// Resolve service instance, None if it has no SRV records
+    fn resolve(
+        &mut self,
+        domain: &str,
+        family: FamilyPreference,
+    ) -> Result<Option<(String, Vec<SocketAddr>, Txt)>> {
+        let mut records = self.query(domain, TYPE_SRV)?;
+        // Servers usually put TXT and addresses into additional section
+        if !records.iter().any(|r| r.type_ == TYPE_TXT) {
+            records.extend(self.query(domain, TYPE_TXT)?);
+        }
+        let srvs: Vec<_> = records
+            .iter()
+            .filter(|r| r.name.eq_ignore_ascii_case(domain))
+            .filter_map(|r| match &r.data {
+                RData::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } => Some((*priority, *weight, *port, target.clone())),
+                _ => None,
+            })
+            .collect();
+        let mut hasher = DefaultHasher::new();
+        domain.hash(&mut hasher);
+        let Some((_priority, _weight, port, hostname)) =
+            select_srv(srvs, |srv| (srv.0, srv.1), hasher.finish())
+        else {
+            return Ok(None);
+        };
+        if !records
+            .iter()
+            .any(|r| matches!(r.data, RData::A(_) | RData::Aaaa(_)))
+        {
+            if family != FamilyPreference::Ipv6 {
+                records.extend(self.query(&hostname, TYPE_A)?);
+            }
+            if family != FamilyPreference::Ipv4 {
+                records.extend(self.query(&hostname, TYPE_AAAA)?);
+            }
+        }
+        let mut sockets = Vec::new();
+        let mut txt = Vec::new();
+        for record in records {
+            match record.data {
+                RData::A(addr) if record.name.eq_ignore_ascii_case(&hostname) => {
+                    sockets.extend(socket_addr(0, AF_INET4, &addr.octets(), port));
+                }
+                // Scope of link-local addresses is unknown, those are rejected
+                RData::Aaaa(addr) if record.name.eq_ignore_ascii_case(&hostname) => {
+                    sockets.extend(socket_addr(0, AF_INET6, &addr.octets(), port));
+                }
+                RData::Txt(strings)
+                    if record.name.eq_ignore_ascii_case(domain) && txt.is_empty() =>
+                {
+                    txt = strings
+                        .into_iter()
+                        // NUL can't be passed in properties
+                        .map(|r| String::from_utf8_lossy(&r).replace('\0', ""))
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        Ok(Some((hostname, sockets, Txt::new(txt))))
+    }
+}
+
+/// Client of the DNS-SD server
+struct Client {
+    socket: UdpSocket,
+    id: u16,
+}
+
+impl Client {
+    fn new(server: SocketAddr, timeout: Duration) -> Result<Self> {
+        let bind: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse().expect("valid address")
+        } else {
+            "[::]:0".parse().expect("valid address")
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(timeout))?;
+        socket.connect(server)?;
+        Ok(Self {
+            socket,
+            // Only used to match responses, doesn't need to be unpredictable
+            id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos() as u16),
+        })
+    }
+}
+
+impl Querier for Client {
+    fn query(&mut self, name: &str, type_: u16) -> Result<Vec<Record>> {
+        self.id = self.id.wrapping_add(1);
+        let query = encode_query(self.id, FLAG_RD, name, type_)
+            .ok_or_else(|| Error::Dns(format!("{name} can't be encoded")))?;
+        self.socket.send(&query)?;
+        let mut buf = [0; 4096];
+        loop {
+            let len = self.socket.recv(&mut buf)?;
+            let data = &buf[..len];
+            let (_rest, message) =
+                parse_message(data).map_err(|e| Error::nom("dns message", data, e))?;
+            if !message.is_response() || message.id != self.id {
+                // Late response to the previous query
+                debug!("unexpected message from the server, id {}", message.id);
+                continue;
+            }
+            if message.is_truncated() {
+                warn!("response for {name} is truncated, some records might be missing");
+            }
+            return match message.rcode() {
+                0 => Ok(message
+                    .records
+                    .into_iter()
+                    .filter(|r| r.class == CLASS_IN)
+                    .collect()),
+                RCODE_NXDOMAIN => Ok(vec![]),
+                rcode => Err(Error::Dns(format!(
+                    "query for {name} failed, rcode {rcode}"
+                ))),
+            };
+        }
+    }
+}
+
+/// Discover devices by querying the DNS-SD server directly, for routed networks
+/// where mDNS doesn't reach the devices, and the server isn't configured in resolved
+///
+/// `last_scan` is updated after every successful scan
+pub(crate) fn unicast_discovery(
+    server: SocketAddr,
+    domain: &str,
+    family: FamilyPreference,
+    timeout: Duration,
+    last_scan: Arc<Mutex<Instant>>,
+    trigger: ScanTrigger,
+) -> Result<Receiver<Event>> {
+    let service = format!("{SERVICE}.{domain}");
+    let client = Client::new(server, timeout)?;
+    info!("discovering {service} using {server}");
+    Ok(discovery(client, service, family, last_scan, trigger))
+}
+
+/// Scan for the service every interval, `last_scan` is updated after every successful scan
+pub(crate) fn discovery(
+    mut client: impl Querier + Send + 'static,
+    service: String,
+    family: FamilyPreference,
+    last_scan: Arc<Mutex<Instant>>,
+    trigger: ScanTrigger,
+) -> Receiver<Event> {
+    let (tx, rx) = channel::channel();
+    std::thread::spawn(move || {
+        let mut known = KnownHosts::default();
+        let mut triggered = 0;
+        loop {
+            match scan(&mut client, &service, family, &mut known, &tx) {
+                Ok(()) => *last_scan.lock().expect("not poisoned") = Instant::now(),
+                Err(Error::ReceiverDead) => {
+                    error!("receiver is dead");
+                    return;
+                }
+                Err(e) => warn_limited(format!("scan failed: {e}")),
+            }
+            trigger.wait(&mut triggered, SCAN_INTERVAL);
+        }
+    });
+    rx
+}
+
+fn scan(
+    client: &mut impl Querier,
+    service: &str,
+    family: FamilyPreference,
+    known: &mut KnownHosts,
+    tx: &Sender<Event>,
+) -> Result<()> {
+    let domains: BTreeSet<String> = client
+        .query(service, TYPE_PTR)?
+        .into_iter()
+        .filter(|r| r.name.eq_ignore_ascii_case(service))
+        .filter_map(|r| match r.data {
+            RData::Ptr(domain) if strip_name_suffix(&domain, service).is_some() => Some(domain),
+            _ => None,
+        })
+        .collect();
+    let mut resolved = BTreeSet::new();
+    for domain in &domains {
+        let (hostname, sockets, txt) = match client.resolve(domain, family) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                warn_limited(format!("no srv records for {domain}"));
+                continue;
+            }
+            Err(e) => {
+                warn_limited(format!("{domain}: {e}"));
+                continue;
+            }
+        };
+        resolved.insert(ResolvedHost {
+            ifindex: 0,
+            name: service.to_owned(),
+            domain: domain.clone(),
+            retries: REMOVAL_RETRIES,
+        });
+        let sockets: Vec<_> = sockets.into_iter().map(|s| (0, s)).collect();
+        for (ifindex, socket) in family.select(&sockets) {
+            tx.send(Event::Discovered(Discovered {
+                hostname: hostname.clone(),
+                socket,
+                ifindex,
+                domain: Some(domain.clone()),
+                instance: instance_name(domain, service),
+                canonical: Some(domain.clone()),
+                txt: txt.clone(),
+            }))
+            .map_err(|_| Error::ReceiverDead)?;
+        }
+    }
+    // Instances which failed to resolve are treated as missing
+    let (added, removed) = known.update(resolved);
+    for added in added {
+        info!("added host: {}", added.domain);
+    }
+    for removed in removed {
+        info!("removed host: {}", removed.domain);
+        tx.send(Event::Removed(removed.domain))
+            .map_err(|_| Error::ReceiverDead)?;
+    }
+    Ok(())
+}